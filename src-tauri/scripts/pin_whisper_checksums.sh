@@ -0,0 +1,13 @@
+#!/bin/bash
+# Prints the `CATALOG` rows of src-tauri/src/whisper.rs with the SHA-256 and size the whisper.cpp
+# repository publishes for each model, ready to replace the existing rows. Needs curl and jq.
+set -euo pipefail
+MODELS='["tiny","tiny.en","tiny-q5_1","tiny.en-q5_1","base","base.en","base-q5_1","base.en-q5_1","small","small.en","small-q5_1","small.en-q5_1","medium","medium.en","medium-q5_0","medium.en-q5_0","large-v3","large-v3-q5_0"]'
+curl -fsSL https://huggingface.co/api/models/ggerganov/whisper.cpp/tree/main \
+  | jq -r --argjson models "$MODELS" '
+      (map(select(.lfs != null)) | map({ key: .path, value: .lfs }) | from_entries) as $files
+      | $models[]
+      | . as $name
+      | $files["ggml-\($name).bin"] as $lfs
+      | if $lfs == null then error("ggml-\($name).bin isn't published") else . end
+      | "    model(\"\($name)\", \(($lfs.size / 1000000) | round), \($name | test("\\.en")), \($name | test("-q")), Some(PinnedChecksum { sha256: \"\($lfs.oid)\", size: \($lfs.size) })),"'
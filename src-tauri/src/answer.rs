@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, ClipboardManager, Manager};
+use tauri::api::dialog::FileDialogBuilder;
+use tokio::sync::oneshot;
+
+/// A piece of a finished answer that the transcription window can show and act on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnswerBlock {
+    Paragraph { text: String },
+    Code { language: Option<String>, code: String },
+    List { ordered: bool, items: Vec<String> },
+    Email { to: Option<String>, subject: String, body: String },
+}
+
+impl AnswerBlock {
+    /// The block as plain text, for the clipboard.
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::Paragraph { text } => text.clone(),
+            Self::Code { code, .. } => code.clone(),
+            Self::List { ordered, items } => items.iter()
+                .enumerate()
+                .map(|(i, item)| if *ordered { format!("{}. {}", i + 1, item) } else { format!("- {}", item) })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Email { subject, body, .. } => format!("Subject: {}\n\n{}", subject, body),
+        }
+    }
+}
+
+/// Splits an answer into blocks. Models answer in markdown, so fenced code, list items and blank
+/// lines between paragraphs are recognised. A paragraph starting with `Subject:` starts an email
+/// draft, which runs until the next code block or the end of the answer.
+pub fn parse_answer(answer: &str) -> Vec<AnswerBlock> {
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut list: Option<(bool, Vec<String>)> = None;
+
+    let mut lines = answer.lines();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            flush_paragraph(&mut blocks, &mut paragraph);
+            flush_list(&mut blocks, &mut list);
+            let code: Vec<&str> = lines.by_ref().take_while(|line| !line.trim_start().starts_with("```")).collect();
+            let language = info.trim().to_lowercase();
+            blocks.push(AnswerBlock::Code {
+                language: if language.is_empty() { None } else { Some(language) },
+                code: code.join("\n"),
+            });
+        } else if trimmed.is_empty() {
+            flush_paragraph(&mut blocks, &mut paragraph);
+            flush_list(&mut blocks, &mut list);
+        } else if let Some((ordered, item)) = list_item(trimmed) {
+            flush_paragraph(&mut blocks, &mut paragraph);
+            match &mut list {
+                Some((list_ordered, items)) if *list_ordered == ordered => items.push(item.to_string()),
+                _ => {
+                    flush_list(&mut blocks, &mut list);
+                    list = Some((ordered, vec![item.to_string()]));
+                }
+            }
+        } else if let (Some((_, items)), true) = (&mut list, line.starts_with(char::is_whitespace)) {
+            // An indented line continues the previous list item
+            if let Some(last) = items.last_mut() {
+                last.push(' ');
+                last.push_str(trimmed);
+            }
+        } else {
+            flush_list(&mut blocks, &mut list);
+            paragraph.push(trimmed);
+        }
+    }
+    flush_paragraph(&mut blocks, &mut paragraph);
+    flush_list(&mut blocks, &mut list);
+
+    extract_email(blocks)
+}
+
+fn flush_paragraph(blocks: &mut Vec<AnswerBlock>, paragraph: &mut Vec<&str>) {
+    if !paragraph.is_empty() {
+        blocks.push(AnswerBlock::Paragraph { text: paragraph.join("\n") });
+        paragraph.clear();
+    }
+}
+
+fn flush_list(blocks: &mut Vec<AnswerBlock>, list: &mut Option<(bool, Vec<String>)>) {
+    if let Some((ordered, items)) = list.take() {
+        blocks.push(AnswerBlock::List { ordered, items });
+    }
+}
+
+/// `- item`, `* item`, `+ item` or `1. item` / `1) item`.
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(bullet) {
+            return Some((false, item.trim()));
+        }
+    }
+    let digits = line.find(|c: char| !c.is_ascii_digit())?;
+    let rest = &line[digits..];
+    if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")) {
+        return Some((true, rest[2..].trim()));
+    }
+    None
+}
+
+/// The value of a `Name: value` header line, ignoring case and markdown bold (`**Subject:**`).
+fn header_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let line = line.trim().trim_start_matches("**");
+    let (key, value) = line.split_once(':')?;
+    if !key.trim().eq_ignore_ascii_case(name) {
+        return None;
+    }
+    Some(value.trim().trim_start_matches("**").trim())
+}
+
+fn extract_email(blocks: Vec<AnswerBlock>) -> Vec<AnswerBlock> {
+    let start = blocks.iter().position(|block| match block {
+        AnswerBlock::Paragraph { text } => text.lines().take(2).any(|line| header_value(line, "subject").is_some()),
+        _ => false,
+    });
+    let Some(start) = start else {
+        return blocks;
+    };
+    let end = blocks[start..].iter()
+        .position(|block| matches!(block, AnswerBlock::Code { .. }))
+        .map_or(blocks.len(), |offset| start + offset);
+
+    let mut to = None;
+    let mut subject = String::new();
+    let mut body = Vec::new();
+    if let AnswerBlock::Paragraph { text } = &blocks[start] {
+        let mut rest = Vec::new();
+        for line in text.lines() {
+            if let Some(value) = header_value(line, "to") {
+                to = Some(value.to_string()).filter(|to| !to.is_empty());
+            } else if let Some(value) = header_value(line, "subject").filter(|_| subject.is_empty()) {
+                subject = value.to_string();
+            } else {
+                rest.push(line);
+            }
+        }
+        if !rest.is_empty() {
+            body.push(rest.join("\n"));
+        }
+    }
+    body.extend(blocks[start + 1..end].iter().map(AnswerBlock::to_text));
+
+    let mut result = blocks[..start].to_vec();
+    result.push(AnswerBlock::Email { to, subject, body: body.join("\n\n") });
+    result.extend_from_slice(&blocks[end..]);
+    result
+}
+
+/// A `mailto:` link for an email draft, percent-encoded as RFC 6068 asks (spaces as `%20`, not `+`).
+pub fn mailto_url(to: Option<&str>, subject: &str, body: &str) -> String {
+    format!(
+        "mailto:{}?subject={}&body={}",
+        percent_encode(to.unwrap_or_default()),
+        percent_encode(subject),
+        // Mail clients expect CRLF line breaks in the body
+        percent_encode(&body.replace("\r\n", "\n").replace('\n', "\r\n")),
+    )
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn file_extension(language: Option<&str>) -> &'static str {
+    match language.unwrap_or_default() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "json" => "json",
+        "html" => "html",
+        "css" => "css",
+        "swift" => "swift",
+        "go" => "go",
+        "java" => "java",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "sql" => "sql",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "markdown" | "md" => "md",
+        _ => "txt",
+    }
+}
+
+#[tauri::command]
+pub fn copy_answer_block(app_handle: AppHandle, block: AnswerBlock) -> Result<(), String> {
+    app_handle.clipboard_manager()
+        .write_text(block.to_text())
+        .map_err(|e| e.to_string())
+}
+
+/// Asks where to save a code block and writes it there. Returns the path, or `None` if the user
+/// cancelled the dialog.
+#[tauri::command]
+pub async fn save_code_block(block: AnswerBlock) -> Result<Option<PathBuf>, String> {
+    let AnswerBlock::Code { language, code } = block else {
+        return Err("Only code blocks can be saved to a file".to_string());
+    };
+
+    let (sender, receiver) = oneshot::channel();
+    FileDialogBuilder::new()
+        .set_file_name(&format!("snippet.{}", file_extension(language.as_deref())))
+        .save_file(move |path| {
+            let _ = sender.send(path);
+        });
+    let Some(path) = receiver.await.unwrap_or(None) else {
+        return Ok(None);
+    };
+
+    tokio::fs::write(&path, code).await.map_err(|e| {
+        error!("Failed to save code block to {}: {}", path.display(), e);
+        e.to_string()
+    })?;
+    info!("Saved code block to {}", path.display());
+    Ok(Some(path))
+}
+
+/// Opens an email draft in the default mail app.
+#[tauri::command]
+pub fn open_email_draft(app_handle: AppHandle, block: AnswerBlock) -> Result<(), String> {
+    let AnswerBlock::Email { to, subject, body } = block else {
+        return Err("Only email drafts can be opened in the mail app".to_string());
+    };
+    tauri::api::shell::open(&app_handle.shell_scope(), mailto_url(to.as_deref(), &subject, &body), None)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_answer_blocks() {
+        let answer = "Here's the fix:\n\n```Rust\nfn main() {\n\n    println!(\"hi\");\n}\n```\nThen:\n1. Build it\n2. Run it\n   with cargo\n\n- done";
+        assert_eq!(parse_answer(answer), vec![
+            AnswerBlock::Paragraph { text: "Here's the fix:".to_string() },
+            AnswerBlock::Code { language: Some("rust".to_string()), code: "fn main() {\n\n    println!(\"hi\");\n}".to_string() },
+            AnswerBlock::Paragraph { text: "Then:".to_string() },
+            AnswerBlock::List { ordered: true, items: vec!["Build it".to_string(), "Run it with cargo".to_string()] },
+            AnswerBlock::List { ordered: false, items: vec!["done".to_string()] },
+        ]);
+    }
+
+    #[test]
+    fn test_email_draft() {
+        let answer = "**Subject:** Running late today\n\nHi Sam,\n\nI'll be 15 minutes late.\n\nThanks,\nAlex";
+        let blocks = parse_answer(answer);
+        assert_eq!(blocks, vec![AnswerBlock::Email {
+            to: None,
+            subject: "Running late today".to_string(),
+            body: "Hi Sam,\n\nI'll be 15 minutes late.\n\nThanks,\nAlex".to_string(),
+        }]);
+        assert_eq!(
+            mailto_url(Some("sam@example.com"), "Running late & sorry", "Hi,\nsee you"),
+            "mailto:sam@example.com?subject=Running%20late%20%26%20sorry&body=Hi%2C%0D%0Asee%20you"
+        );
+    }
+}
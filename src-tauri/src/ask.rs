@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+use log::{error, info};
+use serde::Serialize;
+use tauri::{AppHandle, State};
+use crate::answer::{AnswerBlock, parse_answer};
+use crate::conversation::{ConversationMode, ConversationState};
+use crate::gpt::GptClient;
+use crate::memory::offer_memories_from_exchange;
+use crate::screenshot::screenshot;
+
+const SCREENSHOT_FILE_NAME: &str = "derby_latest_screenshot.png";
+
+#[derive(Debug, Serialize)]
+pub struct AskResponse {
+    pub answer: String,
+    pub blocks: Vec<AnswerBlock>,
+}
+
+/// Answers a question, optionally about what's on screen. The answer is streamed to the windows
+/// with the `gpt_stream_*` and `gpt_chunk_received` events, and returned split into blocks once complete.
+/// New facts about the user found in the exchange are offered afterwards with `memory_candidates`.
+#[tauri::command]
+pub async fn ask(
+    app_handle: AppHandle,
+    gpt_client: State<'_, GptClient>,
+    conversation: State<'_, ConversationState>,
+    question: String,
+    attach_screen: bool,
+    new_conversation: Option<bool>,
+    persona_id: Option<String>,
+) -> Result<AskResponse, String> {
+    let question = question.trim().to_string();
+    if question.is_empty() {
+        return Err("Ask a question first.".to_string());
+    }
+
+    let mut image_paths = Vec::new();
+    if attach_screen {
+        let path = capture_screen().await.map_err(|e| {
+            error!("Failed to capture the screen: {}", e);
+            "Couldn't capture your screen. Check that Derby has the Screen Recording permission.".to_string()
+        })?;
+        image_paths.push(path);
+    }
+
+    let mode = if new_conversation.unwrap_or(false) { ConversationMode::New } else { ConversationMode::Continue };
+    info!("Asking a question ({:?}, screen attached: {})", mode, attach_screen);
+    let answer = gpt_client.ask_in_conversation(&conversation, mode, question.clone(), image_paths, persona_id.as_deref(), app_handle.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    offer_memories_from_exchange(app_handle, question, answer.clone());
+    let blocks = parse_answer(&answer);
+    Ok(AskResponse { answer, blocks })
+}
+
+async fn capture_screen() -> anyhow::Result<PathBuf> {
+    let path = std::env::temp_dir().join(SCREENSHOT_FILE_NAME);
+    tokio::task::spawn_blocking(move || screenshot(path)).await?
+}
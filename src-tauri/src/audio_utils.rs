@@ -3,8 +3,14 @@ use rodio::{Decoder, OutputStream, Sink};
 use bytes::Bytes;
 use std::io::{Cursor, Read};
 use std::path::PathBuf;
-use log::info;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde_json::Value;
+use tauri::AppHandle;
 use rubato::{Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
+use crate::recorder::{AudioRecording, downmix};
+use crate::stores::{get_number_from_store, get_value_from_store};
 
 pub const TARGET_SAMPLE_RATE: usize = 16000;
 fn _clamp(value: f32, min: f32, max: f32) -> f32 {
@@ -74,33 +80,96 @@ pub fn _play_audio_f32_vec(data: Vec<f32>, sample_rate: u32) {
     sink.sleep_until_end();
 }
 
-pub fn resample_audio(mut audio_recording: AudioRecording) -> AudioRecording {
-    info!("Resampling audio from {} to {}", audio_recording.config.sample_rate.0, TARGET_SAMPLE_RATE);
+/// Device audio the streaming resampler converts at once
+const RESAMPLER_CHUNK: Duration = Duration::from_millis(20);
 
-    let sinc_len = 256;
-    let f_cutoff = 0.95;
-    let params = SincInterpolationParameters {
-        sinc_len,
-        f_cutoff,
-        oversampling_factor: 160,
-        interpolation: SincInterpolationType::Cubic,
-        window: WindowFunction::BlackmanHarris2,
-    };
+/// Converts interleaved audio at any rate and channel count to 16 kHz mono as it arrives, a
+/// fixed-size chunk at a time, so audio is only held back for about a chunk and the filter's length.
+pub struct StreamingResampler {
+    channels: usize,
+    /// None when the device already records at `TARGET_SAMPLE_RATE`
+    resampler: Option<SincFixedIn<f32>>,
+    ratio: f64,
+    /// Interleaved samples that don't make a whole frame yet
+    partial_frame: Vec<f32>,
+    /// Mono samples waiting for a whole chunk
+    pending: Vec<f32>,
+    frames_in: usize,
+    frames_out: usize,
+}
+
+impl StreamingResampler {
+    pub fn new(sample_rate: u32, channels: u16) -> Result<Self> {
+        let ratio = TARGET_SAMPLE_RATE as f64 / sample_rate as f64;
+        let resampler = if sample_rate == TARGET_SAMPLE_RATE as u32 {
+            None
+        } else {
+            let params = SincInterpolationParameters {
+                sinc_len: 256,
+                f_cutoff: 0.95,
+                oversampling_factor: 160,
+                interpolation: SincInterpolationType::Cubic,
+                window: WindowFunction::BlackmanHarris2,
+            };
+            let chunk = ((sample_rate as f64 * RESAMPLER_CHUNK.as_secs_f64()) as usize).max(1);
+            Some(SincFixedIn::<f32>::new(ratio, 1.0, params, chunk, 1)
+                .with_context(|| format!("Can't resample audio recorded at {} Hz", sample_rate))?)
+        };
+        Ok(Self {
+            channels: channels.max(1) as usize,
+            resampler,
+            ratio,
+            partial_frame: Vec::new(),
+            pending: Vec::new(),
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
 
-    let mut resampler = SincFixedIn::<f32>::new(
-        TARGET_SAMPLE_RATE as f64 / audio_recording.config.sample_rate.0 as f64,
-        1.0,
-        params,
-        audio_recording.audio_data.len(),
-        1,
-    ).expect("Failed to create resampler");
+    /// Takes the next interleaved samples and returns what could be converted so far.
+    pub fn process(&mut self, interleaved: &[f32]) -> Result<Vec<f32>> {
+        self.partial_frame.extend_from_slice(interleaved);
+        let whole = self.partial_frame.len() - self.partial_frame.len() % self.channels;
+        let mono = downmix(&self.partial_frame[..whole], self.channels as u16);
+        self.partial_frame.drain(..whole);
+        self.frames_in += mono.len();
 
-    let audio_data = vec![audio_recording.audio_data.to_vec()];
+        let Some(resampler) = &mut self.resampler else {
+            self.frames_out += mono.len();
+            return Ok(mono);
+        };
+        self.pending.extend(mono);
+        let mut output = Vec::new();
+        while self.pending.len() >= resampler.input_frames_next() {
+            let chunk: Vec<f32> = self.pending.drain(..resampler.input_frames_next()).collect();
+            output.extend(resampler.process(&[chunk], None)?.into_iter().next().unwrap_or_default());
+        }
+        self.frames_out += output.len();
+        Ok(output)
+    }
 
-    let audio_vec_resampled = resampler.process(&audio_data, None).unwrap();
-    audio_recording.audio_data = audio_vec_resampled.into_iter().flatten().collect();
-    audio_recording.config.sample_rate.0 = TARGET_SAMPLE_RATE as u32;
-    audio_recording
+    /// Converts what's left at the end of the stream, after which the output is exactly as long
+    /// as the input.
+    pub fn flush(&mut self) -> Result<Vec<f32>> {
+        let expected = (self.frames_in as f64 * self.ratio).round() as usize;
+        let Some(resampler) = &mut self.resampler else {
+            return Ok(Vec::new());
+        };
+        let mut output = Vec::new();
+        // Silence pushes the rest of the audio through the filter
+        while self.frames_out + output.len() < expected {
+            let pending = std::mem::take(&mut self.pending);
+            let resampled = if pending.is_empty() {
+                resampler.process_partial::<Vec<f32>>(None, None)?
+            } else {
+                resampler.process_partial(Some(&[pending]), None)?
+            };
+            output.extend(resampled.into_iter().next().unwrap_or_default());
+        }
+        output.truncate(expected.saturating_sub(self.frames_out));
+        self.frames_out = expected;
+        Ok(output)
+    }
 }
 
 pub fn _play_audio_from_wav(path: PathBuf) {
@@ -167,4 +236,327 @@ pub fn _read_from_wav(filename: &str) {
     if sample_rate != 16000 {
         panic!("sample rate must be 16KHz");
     }
-}
\ No newline at end of file
+}
+/// Length of the frames speech is detected in. WebRTC's detector accepts 10, 20 or 30 ms.
+const VAD_FRAME_MS: u32 = 30;
+/// The energy detector's speech has to be this much louder than the background noise
+const NOISE_MARGIN_DB: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectorKind {
+    /// Loudness against a fixed threshold and the background noise
+    Energy,
+    /// WebRTC's statistical model, better at telling speech from other noise
+    WebRtc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VadSettings {
+    pub detector: DetectorKind,
+    /// Frames quieter than this (dBFS) are never speech for the energy detector
+    pub threshold_db: f32,
+    /// How aggressively WebRTC's detector filters out non-speech, 0 to 3
+    pub webrtc_mode: u8,
+    /// Speech has to last this long to count, so clicks and coughs don't
+    pub min_speech: Duration,
+    /// Silence after speech that ends the recording, None to record until stopped
+    pub auto_stop_after: Option<Duration>,
+    /// Audio kept before and after speech when trimming
+    pub padding: Duration,
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        Self {
+            detector: DetectorKind::Energy,
+            threshold_db: -45.0,
+            webrtc_mode: 2,
+            min_speech: Duration::from_millis(150),
+            auto_stop_after: Some(Duration::from_millis(1500)),
+            padding: Duration::from_millis(300),
+        }
+    }
+}
+
+impl VadSettings {
+    /// The settings page's thresholds, or None when `vad_enabled` is off.
+    pub fn from_store(app_handle: &AppHandle) -> Option<Self> {
+        let value = |key: &str| get_value_from_store(app_handle, key);
+        let number = |key: &str| get_number_from_store(app_handle, key);
+        let millis = |key: &str| number(key).filter(|ms| *ms >= 0.0).map(|ms| Duration::from_millis(ms as u64));
+
+        if value("vad_enabled").and_then(|value| value.as_bool()) == Some(false) {
+            return None;
+        }
+        let defaults = Self::default();
+        Some(Self {
+            detector: match value("vad_detector").as_ref().and_then(Value::as_str) {
+                Some("webrtc") => DetectorKind::WebRtc,
+                _ => DetectorKind::Energy,
+            },
+            threshold_db: number("vad_threshold_db").map(|db| db as f32).unwrap_or(defaults.threshold_db),
+            webrtc_mode: number("vad_webrtc_mode").map(|mode| mode.clamp(0.0, 3.0) as u8).unwrap_or(defaults.webrtc_mode),
+            min_speech: millis("vad_min_speech_ms").unwrap_or(defaults.min_speech),
+            // 0 turns auto-stop off
+            auto_stop_after: match millis("vad_auto_stop_ms") {
+                Some(after) if after.is_zero() => None,
+                Some(after) => Some(after),
+                None => defaults.auto_stop_after,
+            },
+            padding: millis("vad_padding_ms").unwrap_or(defaults.padding),
+        })
+    }
+}
+
+pub trait SpeechDetector {
+    fn is_speech(&mut self, frame: &[f32]) -> bool;
+}
+
+/// Loudness of a frame in dBFS.
+fn rms_db(frame: &[f32]) -> f32 {
+    let mean_square = frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len().max(1) as f32;
+    10.0 * mean_square.max(1e-10).log10()
+}
+
+/// Speech is what's louder than both the threshold and the background noise. The noise level
+/// follows quieter frames right away and louder non-speech slowly, so a fan starting up doesn't
+/// count as someone talking.
+pub struct EnergyDetector {
+    threshold_db: f32,
+    noise_db: f32,
+}
+
+impl EnergyDetector {
+    pub fn new(threshold_db: f32) -> Self {
+        Self { threshold_db, noise_db: threshold_db - NOISE_MARGIN_DB }
+    }
+}
+
+impl SpeechDetector for EnergyDetector {
+    fn is_speech(&mut self, frame: &[f32]) -> bool {
+        let db = rms_db(frame);
+        let speech = db > self.threshold_db && db > self.noise_db + NOISE_MARGIN_DB;
+        if db < self.noise_db {
+            self.noise_db = db;
+        } else if !speech {
+            self.noise_db += (db - self.noise_db) * 0.05;
+        }
+        speech
+    }
+}
+
+pub struct WebRtcDetector {
+    vad: webrtc_vad::Vad,
+    samples: Vec<i16>,
+}
+
+impl SpeechDetector for WebRtcDetector {
+    fn is_speech(&mut self, frame: &[f32]) -> bool {
+        self.samples.clear();
+        self.samples.extend(frame.iter().map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16));
+        self.vad.is_voice_segment(&self.samples).unwrap_or(false)
+    }
+}
+
+/// The configured detector. WebRTC's only works at 8, 16, 32 and 48 kHz, at other rates the
+/// energy detector is used instead.
+fn speech_detector(settings: &VadSettings, sample_rate: u32) -> Box<dyn SpeechDetector> {
+    if settings.detector == DetectorKind::WebRtc {
+        let rate = match sample_rate {
+            8000 => Some(webrtc_vad::SampleRate::Rate8kHz),
+            16000 => Some(webrtc_vad::SampleRate::Rate16kHz),
+            32000 => Some(webrtc_vad::SampleRate::Rate32kHz),
+            48000 => Some(webrtc_vad::SampleRate::Rate48kHz),
+            _ => None,
+        };
+        let mode = match settings.webrtc_mode {
+            0 => webrtc_vad::VadMode::Quality,
+            1 => webrtc_vad::VadMode::LowBitrate,
+            2 => webrtc_vad::VadMode::Aggressive,
+            _ => webrtc_vad::VadMode::VeryAggressive,
+        };
+        match rate {
+            Some(rate) => return Box::new(WebRtcDetector { vad: webrtc_vad::Vad::new_with_rate_and_mode(rate, mode), samples: Vec::new() }),
+            None => warn!("The WebRTC speech detector doesn't support {} Hz, detecting speech by loudness", sample_rate),
+        }
+    }
+    Box::new(EnergyDetector::new(settings.threshold_db))
+}
+
+/// A stretch of speech, in seconds since the detector started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeechSegment {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Tracks where the speech is in a stream of mono audio.
+pub struct VoiceActivityDetector {
+    detector: Box<dyn SpeechDetector>,
+    settings: VadSettings,
+    sample_rate: u32,
+    frame_len: usize,
+    /// Samples of the next, incomplete frame
+    frame: Vec<f32>,
+    frames: usize,
+    /// Consecutive speech frames, speech only counts once there are enough of them
+    speech_frames: usize,
+    /// Whether the last segment is still going
+    in_segment: bool,
+    segments: Vec<SpeechSegment>,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(settings: &VadSettings, sample_rate: u32) -> Self {
+        let frame_len = (sample_rate * VAD_FRAME_MS / 1000).max(1) as usize;
+        Self {
+            detector: speech_detector(settings, sample_rate),
+            settings: settings.clone(),
+            sample_rate,
+            frame_len,
+            frame: Vec::with_capacity(frame_len),
+            frames: 0,
+            speech_frames: 0,
+            in_segment: false,
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.frame.push(sample);
+            if self.frame.len() == self.frame_len {
+                let frame = std::mem::take(&mut self.frame);
+                self.process_frame(&frame);
+                self.frame = frame;
+                self.frame.clear();
+            }
+        }
+    }
+
+    fn process_frame(&mut self, frame: &[f32]) {
+        let speech = self.detector.is_speech(frame);
+        self.frames += 1;
+        let frame_end = self.processed();
+        if !speech {
+            self.speech_frames = 0;
+            self.in_segment = false;
+            return;
+        }
+        self.speech_frames += 1;
+        let speech_secs = (self.speech_frames * self.frame_len) as f64 / self.sample_rate as f64;
+        if self.in_segment {
+            if let Some(last) = self.segments.last_mut() {
+                last.end = frame_end;
+            }
+        } else if speech_secs >= self.settings.min_speech.as_secs_f64() {
+            self.segments.push(SpeechSegment { start: frame_end - speech_secs, end: frame_end });
+            self.in_segment = true;
+        }
+    }
+
+    /// Seconds of audio in the frames processed so far.
+    pub fn processed(&self) -> f64 {
+        (self.frames * self.frame_len) as f64 / self.sample_rate as f64
+    }
+
+    pub fn segments(&self) -> &[SpeechSegment] {
+        &self.segments
+    }
+
+    /// Whether there was speech after `time`.
+    pub fn speech_after(&self, time: f64) -> bool {
+        self.segments.last().is_some_and(|last| last.end > time)
+    }
+
+    /// Whether someone spoke and has been quiet for long enough to end the recording.
+    pub fn should_stop(&self) -> bool {
+        match (self.settings.auto_stop_after, self.segments.last()) {
+            (Some(after), Some(last)) => self.processed() - last.end >= after.as_secs_f64(),
+            _ => false,
+        }
+    }
+
+    /// From the start of the first speech to the end of the last, with padding on both sides.
+    pub fn speech_bounds(&self) -> Option<SpeechSegment> {
+        let padding = self.settings.padding.as_secs_f64();
+        let (first, last) = (self.segments.first()?, self.segments.last()?);
+        Some(SpeechSegment {
+            start: (first.start - padding).max(0.0),
+            end: (last.end + padding).min(self.processed() + self.frame.len() as f64 / self.sample_rate as f64),
+        })
+    }
+}
+
+/// Cuts the silence before and after the speech. Audio without any speech is dropped entirely,
+/// so Whisper doesn't make up words for it.
+pub fn trim_silence(audio: &[f32], sample_rate: u32, settings: &VadSettings) -> Vec<f32> {
+    let mut vad = VoiceActivityDetector::new(settings, sample_rate);
+    vad.process(audio);
+    match vad.speech_bounds() {
+        Some(bounds) => {
+            let start = ((bounds.start * sample_rate as f64) as usize).min(audio.len());
+            let end = ((bounds.end * sample_rate as f64).ceil() as usize).clamp(start, audio.len());
+            audio[start..end].to_vec()
+        }
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(seconds: f64, amplitude: f32) -> Vec<f32> {
+        (0..(seconds * TARGET_SAMPLE_RATE as f64) as usize)
+            .map(|i| (i as f32 * 220.0 * std::f32::consts::TAU / TARGET_SAMPLE_RATE as f32).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn test_vad_finds_speech_stops_after_silence_and_trims() {
+        let settings = VadSettings::default();
+        let audio = [tone(0.5, 0.001), tone(1.0, 0.3), tone(2.0, 0.001)].concat();
+
+        let mut vad = VoiceActivityDetector::new(&settings, TARGET_SAMPLE_RATE as u32);
+        vad.process(&audio[..TARGET_SAMPLE_RATE * 2]);
+        assert_eq!(vad.segments().len(), 1);
+        let speech = vad.segments()[0];
+        assert!((speech.start - 0.5).abs() < 0.05 && (speech.end - 1.5).abs() < 0.05, "{:?}", speech);
+        assert!(!vad.should_stop());
+        vad.process(&audio[TARGET_SAMPLE_RATE * 2..]);
+        assert!(vad.should_stop());
+
+        let trimmed = trim_silence(&audio, TARGET_SAMPLE_RATE as u32, &settings);
+        let expected = (1.0 + 2.0 * settings.padding.as_secs_f64()) * TARGET_SAMPLE_RATE as f64;
+        assert!((trimmed.len() as f64 - expected).abs() < 0.05 * TARGET_SAMPLE_RATE as f64, "{}", trimmed.len());
+        assert!(trim_silence(&tone(1.0, 0.001), TARGET_SAMPLE_RATE as u32, &settings).is_empty());
+    }
+
+    #[test]
+    fn test_streaming_resampler() {
+        // A click half a second into a stereo recording, fed in pieces that split frames
+        let rate = 44_100;
+        let mut input = vec![0.0; rate * 2];
+        input[rate] = 1.0;
+        input[rate + 1] = 1.0;
+        let mut resampler = StreamingResampler::new(rate as u32, 2).unwrap();
+        let mut output = Vec::new();
+        for piece in input.chunks(1001) {
+            output.extend(resampler.process(piece).unwrap());
+            assert!(resampler.pending.len() <= rate / 50);
+        }
+        output.extend(resampler.flush().unwrap());
+        assert_eq!(output.len(), 16_000);
+        let click = output.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).unwrap().0;
+        assert!((7_999..=8_001).contains(&click));
+
+        for (rate, channels) in [(8_000, 1), (48_000, 6), (16_000, 2)] {
+            let mut resampler = StreamingResampler::new(rate, channels).unwrap();
+            let mut output = resampler.process(&vec![0.1; rate as usize / 2 * channels as usize]).unwrap();
+            output.extend(resampler.flush().unwrap());
+            assert_eq!(output.len(), 8_000);
+            assert!((output[4_000] - 0.1).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use crate::gpt_error::GptError;
+
+/// Record every provider exchange into the cassette file at this path
+pub const RECORD_CASSETTE_ENV: &str = "DERBY_RECORD_CASSETTE";
+/// Answer every provider request from the cassette file at this path instead of the network
+pub const REPLAY_CASSETTE_ENV: &str = "DERBY_REPLAY_CASSETTE";
+
+/// The response headers the app looks at. Everything else (cookies, organisation ids, ...) is
+/// left out of cassettes so they can be committed.
+const RECORDED_HEADERS: &[&str] = &["content-type", "retry-after", "retry-after-ms"];
+
+/// A recording of HTTP exchanges with the providers, stored as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    /// Path and query of the request, so a cassette works whatever the base URL
+    pub path: String,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// The body exactly as it came off the socket
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// Time since the previous chunk, or since the response headers for the first one
+    pub delay_ms: u64,
+    /// Base64 encoded, since a chunk can end in the middle of a UTF-8 character
+    pub data: String,
+}
+
+impl Chunk {
+    pub fn new(delay_ms: u64, data: &[u8]) -> Self {
+        Self {
+            delay_ms,
+            data: general_purpose::STANDARD.encode(data),
+        }
+    }
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// A response whose body is streamed from the network or replayed from a cassette.
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Bytes, GptError>>,
+}
+
+impl HttpResponse {
+    pub async fn text(mut self) -> Result<String, GptError> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.body.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+/// Sends the provider requests. Besides going to the network it can record the exchanges into a
+/// cassette, or replay a cassette with the original chunk boundaries and timings.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    /// Shared by every clone, so new network settings apply everywhere at once
+    client: Arc<RwLock<Client>>,
+    /// Limit for a whole chat request, shared like the client
+    request_timeout: Arc<RwLock<Option<Duration>>>,
+    mode: TransportMode,
+}
+
+#[derive(Debug, Clone)]
+enum TransportMode {
+    Live,
+    Record(Arc<Recorder>),
+    Replay(Arc<Replayer>),
+}
+
+impl Transport {
+    fn new(client: Client, mode: TransportMode) -> Self {
+        Self { client: Arc::new(RwLock::new(client)), request_timeout: Arc::default(), mode }
+    }
+
+    pub fn live(client: Client) -> Self {
+        Self::new(client, TransportMode::Live)
+    }
+
+    pub fn record(client: Client, path: PathBuf) -> Self {
+        Self::new(client, TransportMode::Record(Arc::new(Recorder::new(path))))
+    }
+
+    pub fn replay(cassette: Cassette) -> Self {
+        Self::new(Client::new(), TransportMode::Replay(Arc::new(Replayer::new(cassette))))
+    }
+
+    /// Picks the mode from `DERBY_RECORD_CASSETTE` / `DERBY_REPLAY_CASSETTE`, going to the network
+    /// through `client` by default.
+    pub fn from_env(client: Client) -> Self {
+        if let Ok(path) = env::var(REPLAY_CASSETTE_ENV) {
+            info!("Replaying provider responses from {}", path);
+            let cassette = Cassette::load(Path::new(&path)).unwrap_or_else(|e| {
+                // Falling back to the network could spend money during a test run, so replay nothing instead
+                error!("Failed to load cassette {}: {}", path, e);
+                Cassette::default()
+            });
+            return Self::replay(cassette);
+        }
+        if let Ok(path) = env::var(RECORD_CASSETTE_ENV) {
+            info!("Recording provider responses to {}", path);
+            return Self::record(client, PathBuf::from(path));
+        }
+        Self::live(client)
+    }
+
+    /// The client to build requests with. Replayed requests are built the same way but never sent.
+    pub fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn set_client(&self, client: Client) {
+        *self.client.write().unwrap() = client;
+    }
+
+    /// The limit for chat requests from the network settings, None for no limit.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        *self.request_timeout.read().unwrap()
+    }
+
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        *self.request_timeout.write().unwrap() = timeout;
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<HttpResponse, GptError> {
+        let request = request.build().map_err(|e| GptError::from_reqwest(&e))?;
+        let method = request.method().to_string();
+        let path = match request.url().query() {
+            Some(query) => format!("{}?{}", request.url().path(), query),
+            None => request.url().path().to_string(),
+        };
+
+        let recorder = match &self.mode {
+            TransportMode::Replay(replayer) => return replayer.replay(&method, &path),
+            TransportMode::Record(recorder) => Some(recorder.clone()),
+            TransportMode::Live => None,
+        };
+
+        let response = self.client().execute(request).await.map_err(|e| GptError::from_reqwest(&e))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes_stream().boxed();
+        let body = match recorder {
+            Some(recorder) => recorder.record(method, path, status, &headers, body),
+            None => body.map(|item| item.map_err(|e| GptError::from_reqwest(&e))).boxed(),
+        };
+        Ok(HttpResponse { status, headers, body })
+    }
+}
+
+#[derive(Debug)]
+struct Recorder {
+    path: PathBuf,
+    cassette: Mutex<Cassette>,
+}
+
+impl Recorder {
+    fn new(path: PathBuf) -> Self {
+        // Recording again appends to the cassette, so several exchanges can be captured one after the other
+        let cassette = Cassette::load(&path).unwrap_or_default();
+        Self { path, cassette: Mutex::new(cassette) }
+    }
+
+    /// Passes the body through while timing and copying every chunk. The cassette is saved once
+    /// the body is dropped, so exchanges that were cancelled, failed or timed out are kept too.
+    fn record(
+        self: Arc<Self>,
+        method: String,
+        path: String,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: BoxStream<'static, reqwest::Result<Bytes>>,
+    ) -> BoxStream<'static, Result<Bytes, GptError>> {
+        let headers = RECORDED_HEADERS.iter()
+            .filter_map(|name| Some((name.to_string(), headers.get(*name)?.to_str().ok()?.to_string())))
+            .collect();
+        let index = {
+            let mut cassette = self.cassette.lock().unwrap();
+            cassette.interactions.push(Interaction { method, path, status: status.as_u16(), headers, chunks: Vec::new() });
+            cassette.interactions.len() - 1
+        };
+
+        stream::unfold((body, Instant::now(), SaveOnDrop(self)), move |(mut body, last_chunk, guard)| async move {
+            match body.next().await {
+                Some(Ok(bytes)) => {
+                    let delay_ms = last_chunk.elapsed().as_millis() as u64;
+                    guard.0.cassette.lock().unwrap().interactions[index].chunks.push(Chunk::new(delay_ms, &bytes));
+                    Some((Ok(bytes), (body, Instant::now(), guard)))
+                }
+                Some(Err(e)) => Some((Err(GptError::from_reqwest(&e)), (body, last_chunk, guard))),
+                None => None,
+            }
+        }).boxed()
+    }
+
+    fn save(&self) {
+        let cassette = self.cassette.lock().unwrap();
+        match cassette.save(&self.path) {
+            Ok(()) => info!("Saved {} interactions to {}", cassette.interactions.len(), self.path.display()),
+            Err(e) => error!("Failed to save cassette {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Saves the cassette when the recorded body goes away, whether it was read to the end or not.
+struct SaveOnDrop(Arc<Recorder>);
+
+impl Drop for SaveOnDrop {
+    fn drop(&mut self) {
+        self.0.save();
+    }
+}
+
+#[derive(Debug)]
+struct Replayer {
+    cassette: Cassette,
+    used: Mutex<Vec<bool>>,
+}
+
+impl Replayer {
+    fn new(cassette: Cassette) -> Self {
+        let used = Mutex::new(vec![false; cassette.interactions.len()]);
+        Self { cassette, used }
+    }
+
+    /// Answers with the first interaction for the same method and path that hasn't been replayed
+    /// yet, so a retried request gets the next recorded response.
+    fn replay(&self, method: &str, path: &str) -> Result<HttpResponse, GptError> {
+        let mut used = self.used.lock().unwrap();
+        let interactions = &self.cassette.interactions;
+        let index = (0..interactions.len())
+            .find(|&i| !used[i] && interactions[i].method == method && interactions[i].path == path)
+            .ok_or_else(|| {
+                warn!("No recorded response left for {} {}", method, path);
+                GptError::Network(format!("No recorded response for {} {}", method, path))
+            })?;
+        used[index] = true;
+
+        let interaction = &interactions[index];
+        let status = StatusCode::from_u16(interaction.status)
+            .map_err(|e| GptError::Network(format!("Invalid status in cassette: {}", e)))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &interaction.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::from_str(value)) {
+                headers.insert(name, value);
+            }
+        }
+
+        let body = stream::iter(interaction.chunks.clone())
+            .then(|chunk| async move {
+                tokio::time::sleep(Duration::from_millis(chunk.delay_ms)).await;
+                general_purpose::STANDARD.decode(&chunk.data)
+                    .map(Bytes::from)
+                    .map_err(|e| GptError::Network(format!("Invalid chunk in cassette: {}", e)))
+            })
+            .boxed();
+        Ok(HttpResponse { status, headers, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_keeps_chunk_boundaries_and_timing() {
+        // "é" is split across the two chunks
+        let data = "data: {\"text\": \"café\"}\n\n".as_bytes();
+        let split = data.len() - 5;
+        let cassette = Cassette {
+            interactions: vec![Interaction {
+                method: "POST".to_string(),
+                path: "/v1/chat/completions".to_string(),
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/event-stream".to_string())],
+                chunks: vec![Chunk::new(120, &data[..split]), Chunk::new(40, &data[split..])],
+            }],
+        };
+        // Replays what was written to disk, not the in-memory copy
+        let cassette: Cassette = serde_json::from_str(&serde_json::to_string(&cassette).unwrap()).unwrap();
+        let transport = Transport::replay(cassette);
+
+        let started = tokio::time::Instant::now();
+        let request = transport.client().post("https://api.openai.com/v1/chat/completions");
+        let mut response = transport.send(request).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers["content-type"], "text/event-stream");
+
+        let first = response.body.next().await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(120));
+        assert_eq!(&first[..], &data[..split]);
+        let second = response.body.next().await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(160));
+        assert_eq!(&second[..], &data[split..]);
+        assert!(response.body.next().await.is_none());
+
+        // Each interaction is only replayed once
+        let request = transport.client().post("https://api.openai.com/v1/chat/completions");
+        assert!(matches!(transport.send(request).await, Err(GptError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn test_recording_is_saved_when_the_body_is_dropped() {
+        let path = env::temp_dir().join(format!("derby_cassette_test_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Arc::new(Recorder::new(path.clone()));
+        // A stream that stalls after its first chunk, like a request that's then cancelled
+        let first: reqwest::Result<Bytes> = Ok(Bytes::from_static(b"data: {}\n\n"));
+        let body = stream::iter([first]).chain(stream::pending()).boxed();
+
+        let mut recorded = recorder.record("POST".to_string(), "/v1/chat/completions".to_string(), StatusCode::OK, &HeaderMap::new(), body);
+        assert_eq!(&recorded.next().await.unwrap().unwrap()[..], b"data: {}\n\n");
+        assert!(!path.exists());
+        drop(recorded);
+
+        let cassette = Cassette::load(&path).unwrap();
+        assert_eq!(cassette.interactions.len(), 1);
+        assert_eq!(cassette.interactions[0].chunks.len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,114 @@
+use async_openai::types::Role;
+use log::info;
+use crate::gpt_error::GptError;
+use crate::images::replace_images_with_placeholder;
+use crate::providers::{ChatRequest, context_length, ProviderKind};
+use crate::usage::estimate_prompt_tokens;
+
+/// Token estimates are rough, so requests are kept a bit below the real limit
+const CONTEXT_SAFETY_MARGIN: f64 = 0.9;
+/// Once the history takes up more than this share of the prompt budget it gets summarised
+pub const SUMMARY_THRESHOLD: f64 = 0.5;
+/// Exchanges that are always kept word for word when the rest of the history is summarised
+pub const KEEP_RECENT_EXCHANGES: usize = 2;
+
+/// How many prompt tokens a request may use, leaving room for the answer.
+pub fn prompt_budget(provider: ProviderKind, model: &str, max_tokens: u32) -> u32 {
+    let available = context_length(provider, model).saturating_sub(max_tokens);
+    (available as f64 * CONTEXT_SAFETY_MARGIN) as u32
+}
+
+/// Shrinks the request until its estimated size fits the model's context: first the images of
+/// older turns go, oldest first, then the oldest turns themselves. The system messages at the
+/// start and the latest user turn (with its images) are never touched, if they don't fit on their
+/// own the request fails with `ContextLength`.
+pub fn fit_to_context(provider: ProviderKind, model: &str, request: &mut ChatRequest) -> Result<(), GptError> {
+    let budget = prompt_budget(provider, model, request.params.max_tokens);
+    let fits = |request: &ChatRequest| estimate_prompt_tokens(provider, request) <= budget;
+    if fits(request) {
+        return Ok(());
+    }
+
+    let history_start = request.messages.iter().take_while(|msg| msg.role == Role::System).count();
+    let Some(mut latest_user) = request.messages.iter().rposition(|msg| msg.role == Role::User) else {
+        return Err(GptError::ContextLength("The request has no question".to_string()));
+    };
+
+    for index in history_start..latest_user {
+        if !request.messages[index].images.is_empty() {
+            replace_images_with_placeholder(&mut request.messages[index]);
+            if fits(request) {
+                info!("Dropped older screenshots to fit the context of {}", model);
+                return Ok(());
+            }
+        }
+    }
+
+    let mut dropped = 0;
+    while history_start < latest_user {
+        // Turns are dropped whole so the history still starts with a user message, which Anthropic requires
+        request.messages.remove(history_start);
+        latest_user -= 1;
+        dropped += 1;
+        while history_start < latest_user && request.messages[history_start].role != Role::User {
+            request.messages.remove(history_start);
+            latest_user -= 1;
+            dropped += 1;
+        }
+        if fits(request) {
+            info!("Dropped the {} oldest messages to fit the context of {}", dropped, model);
+            return Ok(());
+        }
+    }
+
+    Err(GptError::ContextLength(format!(
+        "The question is about {} tokens, but {} only has room for {}",
+        estimate_prompt_tokens(provider, request), model, budget
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::ImageAttachment;
+    use crate::providers::{ChatMessage, GenerationParams, ImageDetail};
+
+    fn chat_request(messages: Vec<ChatMessage>) -> ChatRequest {
+        ChatRequest {
+            messages,
+            image_detail: ImageDetail::High,
+            params: GenerationParams { max_tokens: 1024, temperature: None, top_p: None, stop: Vec::new() },
+            tools: Vec::new(),
+            tool_rounds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_trims_images_then_oldest_turns() {
+        // Not a PNG, so every image is counted as a 1080p screenshot (1105 tokens at high detail)
+        let screenshot = ImageAttachment::from_bytes(&[0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        let long_answer = "word ".repeat(2000);
+        let mut request = chat_request(vec![
+            ChatMessage::new(Role::System, "You are Derby"),
+            ChatMessage::new(Role::User, "first").with_images(vec![screenshot.clone()]),
+            ChatMessage::new(Role::Assistant, long_answer.clone()),
+            ChatMessage::new(Role::User, "second").with_images(vec![screenshot.clone()]),
+            ChatMessage::new(Role::Assistant, "short"),
+            ChatMessage::new(Role::User, "latest").with_images(vec![screenshot.clone()]),
+        ]);
+
+        // Ollama has 4096 tokens, minus 1024 for the answer: the images of older turns have to go first
+        fit_to_context(ProviderKind::Ollama, "llava", &mut request).unwrap();
+        let roles: Vec<Role> = request.messages.iter().map(|msg| msg.role.clone()).collect();
+        assert_eq!(roles, vec![Role::System, Role::User, Role::Assistant, Role::User]);
+        assert_eq!(request.messages[1].content.lines().next(), Some("second"));
+        assert!(request.messages[1].images.is_empty());
+        assert_eq!(request.messages[3].images.len(), 1);
+
+        let mut too_long = chat_request(vec![
+            ChatMessage::new(Role::System, "You are Derby"),
+            ChatMessage::new(Role::User, long_answer.repeat(3)),
+        ]);
+        assert!(matches!(fit_to_context(ProviderKind::Ollama, "llava", &mut too_long), Err(GptError::ContextLength(_))));
+    }
+}
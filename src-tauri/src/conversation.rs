@@ -0,0 +1,177 @@
+use std::sync::Mutex;
+use async_openai::types::Role;
+use log::info;
+use serde::Serialize;
+use tauri::State;
+use crate::providers::ChatMessage;
+use crate::usage::estimate_text_tokens;
+
+/// A single thread of alternating user and assistant turns. The system message isn't part of the
+/// thread, it's built fresh for every request so changes on the settings page apply straight away.
+/// Each user turn keeps the images that were sent with it. Once the thread gets long, its older
+/// turns are replaced by a summary.
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    messages: Vec<ChatMessage>,
+    summary: Option<String>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    /// The messages to send for a new question, without adding it to the thread yet (it's only
+    /// recorded once the answer has streamed back successfully).
+    pub fn messages_with_question(&self, system_messages: Vec<ChatMessage>, question: ChatMessage) -> Vec<ChatMessage> {
+        let mut messages = system_messages;
+        if let Some(summary) = &self.summary {
+            messages.push(ChatMessage::new(Role::System, format!("Summary of the conversation so far:\n{}", summary)));
+        }
+        messages.extend(self.messages.iter().cloned());
+        messages.push(question);
+        messages
+    }
+
+    pub fn push_exchange(&mut self, question: ChatMessage, answer: String) {
+        self.messages.push(question);
+        self.messages.push(ChatMessage::new(Role::Assistant, answer));
+    }
+
+    pub fn turn_count(&self) -> usize {
+        self.messages.iter().filter(|msg| msg.role == Role::User).count()
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Rough size of the history in tokens, not counting images which are pruned separately.
+    pub fn estimated_tokens(&self) -> u32 {
+        self.summary.as_deref().map_or(0, estimate_text_tokens)
+            + self.messages.iter().map(|msg| 4 + estimate_text_tokens(&msg.content)).sum::<u32>()
+    }
+
+    /// The turns that would be summarised, i.e. everything but the last `keep_exchanges` exchanges.
+    pub fn turns_to_summarise(&self, keep_exchanges: usize) -> &[ChatMessage] {
+        let keep = (keep_exchanges * 2).min(self.messages.len());
+        &self.messages[..self.messages.len() - keep]
+    }
+
+    /// Replaces the first `summarised` messages with the summary, which already covers any earlier one.
+    pub fn apply_summary(&mut self, summarised: usize, summary: String) {
+        self.messages.drain(..summarised.min(self.messages.len()));
+        self.summary = Some(summary);
+    }
+}
+
+/// Whether a question continues the current thread or starts a fresh one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationMode {
+    New,
+    Continue,
+}
+
+/// The conversation shared between the Tauri commands, managed by the app.
+#[derive(Debug, Default)]
+pub struct ConversationState(pub Mutex<Conversation>);
+
+impl ConversationState {
+    /// Returns a copy of the thread to build the next request from, resetting it first if needed.
+    pub fn begin(&self, mode: ConversationMode) -> Conversation {
+        let mut conversation = self.0.lock().unwrap();
+        if mode == ConversationMode::New {
+            *conversation = Conversation::new();
+        }
+        conversation.clone()
+    }
+
+    /// Stores a summary made from a copy of the thread. It's dropped if the thread was reset in the meantime.
+    pub fn apply_summary(&self, summarised: &[ChatMessage], summary: String) {
+        let mut conversation = self.0.lock().unwrap();
+        if conversation.messages.starts_with(summarised) {
+            conversation.apply_summary(summarised.len(), summary);
+            info!("Summarised {} messages of the conversation", summarised.len());
+        }
+    }
+
+    pub fn record_exchange(&self, question: ChatMessage, answer: String) {
+        let mut conversation = self.0.lock().unwrap();
+        conversation.push_exchange(question, answer);
+        info!("Conversation now has {} turns", conversation.turn_count());
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationTurn {
+    role: String,
+    content: String,
+    image_count: usize,
+}
+
+#[tauri::command]
+pub fn new_conversation(state: State<'_, ConversationState>) {
+    info!("Starting a new conversation");
+    *state.0.lock().unwrap() = Conversation::new();
+}
+
+#[tauri::command]
+pub fn get_conversation(state: State<'_, ConversationState>) -> Vec<ConversationTurn> {
+    state.0.lock().unwrap()
+        .messages()
+        .iter()
+        .map(|msg| ConversationTurn {
+            role: msg.role.to_string(),
+            content: msg.content.clone(),
+            image_count: msg.images.len(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continue_keeps_previous_turns() {
+        let state = ConversationState::default();
+        state.record_exchange(ChatMessage::new(Role::User, "Write a haiku about rust"), "Red flakes on iron".to_string());
+
+        let system = vec![ChatMessage::new(Role::System, "You are Derby")];
+        let messages = state.begin(ConversationMode::Continue).messages_with_question(system, ChatMessage::new(Role::User, "now shorten that"));
+        let roles: Vec<Role> = messages.iter().map(|msg| msg.role.clone()).collect();
+        assert_eq!(roles, vec![Role::System, Role::User, Role::Assistant, Role::User]);
+        assert_eq!(messages[2].content, "Red flakes on iron");
+    }
+
+    #[test]
+    fn test_summary_replaces_older_turns() {
+        let state = ConversationState::default();
+        for i in 0..3 {
+            state.record_exchange(ChatMessage::new(Role::User, format!("question {}", i)), format!("answer {}", i));
+        }
+        let snapshot = state.begin(ConversationMode::Continue);
+        let summarised = snapshot.turns_to_summarise(1).to_vec();
+        assert_eq!(summarised.len(), 4);
+        state.apply_summary(&summarised, "Asked two questions".to_string());
+
+        let messages = state.begin(ConversationMode::Continue).messages_with_question(Vec::new(), ChatMessage::new(Role::User, "next"));
+        assert_eq!(messages[0].content, "Summary of the conversation so far:\nAsked two questions");
+        assert_eq!(messages[1].content, "question 2");
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn test_new_conversation_drops_previous_turns() {
+        let state = ConversationState::default();
+        state.record_exchange(ChatMessage::new(Role::User, "first"), "answer".to_string());
+
+        let conversation = state.begin(ConversationMode::New);
+        assert_eq!(conversation.turn_count(), 0);
+        assert_eq!(state.0.lock().unwrap().turn_count(), 0);
+    }
+}
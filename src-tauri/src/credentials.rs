@@ -0,0 +1,265 @@
+use log::info;
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{AppHandle, State};
+use crate::cassette::{HttpResponse, Transport};
+use crate::gpt::GptClient;
+use crate::gpt_error::GptError;
+use crate::providers::{
+    ANTHROPIC_BASE_URL, ANTHROPIC_DEFAULT_MODEL, ANTHROPIC_VERSION, AZURE_DEFAULT_API_VERSION, OLLAMA_BASE_URL,
+    OLLAMA_DEFAULT_MODEL, OPENAI_BASE_URL, OPENAI_DEFAULT_MODEL, ProviderKind, supports_vision, with_openai_account_headers,
+};
+use crate::secrets::get_secret;
+use crate::stores::get_string_from_store;
+
+/// The credentials to check. Anything the caller leaves out is taken from the settings, so the
+/// first run page can check just a key while the settings page checks everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Credentials {
+    pub provider: Option<String>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub azure_deployment: Option<String>,
+    pub azure_api_version: Option<String>,
+    /// The models the user wants to use, the provider's default if empty
+    pub models: Vec<String>,
+}
+
+impl Credentials {
+    fn or_from_store(self, app_handle: &AppHandle) -> Self {
+        let stored = |key: &str| get_string_from_store(app_handle, key).filter(|value| !value.trim().is_empty());
+        let given = |value: Option<String>| value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+        Self {
+            provider: given(self.provider).or_else(|| stored("provider")),
+            api_key: given(self.api_key).or_else(|| get_secret(app_handle, "api_token")),
+            base_url: given(self.base_url).or_else(|| stored("provider_base_url")),
+            organization: given(self.organization).or_else(|| stored("openai_organization")),
+            project: given(self.project).or_else(|| stored("openai_project")),
+            azure_deployment: given(self.azure_deployment).or_else(|| stored("azure_deployment")),
+            azure_api_version: given(self.azure_api_version).or_else(|| stored("azure_api_version")),
+            models: if self.models.is_empty() { stored("model").into_iter().collect() } else { self.models },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelAccess {
+    pub model: String,
+    pub accessible: bool,
+    pub vision: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub provider: &'static str,
+    /// Whether the provider accepted the credentials
+    pub valid: bool,
+    /// Why the credentials were rejected, or what's missing if they were accepted
+    pub message: Option<String>,
+    pub models: Vec<ModelAccess>,
+    /// A model that can look at screenshots: a configured one if possible, otherwise any the key can use
+    pub vision_model: Option<String>,
+}
+
+impl ValidationReport {
+    fn rejected(provider: ProviderKind, message: String) -> Self {
+        Self { provider: provider.as_store_value(), valid: false, message: Some(message), models: Vec::new(), vision_model: None }
+    }
+}
+
+/// Checks the credentials against the provider, listing its models where the API allows it.
+/// Transport failures and unexpected responses are errors, rejected credentials are a report.
+pub async fn validate_credentials_with(transport: &Transport, credentials: &Credentials) -> Result<ValidationReport, String> {
+    let provider = match credentials.provider.as_deref() {
+        Some(value) => ProviderKind::from_store_value(value).ok_or_else(|| format!("Unknown provider: {}", value))?,
+        None => ProviderKind::OpenAi,
+    };
+    if provider != ProviderKind::Ollama && credentials.api_key.is_none() {
+        return Ok(ValidationReport::rejected(provider, "Enter an API key first.".to_string()));
+    }
+    info!("Validating {} credentials", provider.as_store_value());
+    match provider {
+        ProviderKind::AzureOpenAi => validate_azure(transport, credentials).await,
+        _ => validate_by_listing_models(transport, provider, credentials).await,
+    }
+}
+
+async fn validate_by_listing_models(transport: &Transport, provider: ProviderKind, credentials: &Credentials) -> Result<ValidationReport, String> {
+    let (default_base_url, default_model) = match provider {
+        ProviderKind::Anthropic => (ANTHROPIC_BASE_URL, ANTHROPIC_DEFAULT_MODEL),
+        ProviderKind::Ollama => (OLLAMA_BASE_URL, OLLAMA_DEFAULT_MODEL),
+        _ => (OPENAI_BASE_URL, OPENAI_DEFAULT_MODEL),
+    };
+    let base_url = credentials.base_url.as_deref().unwrap_or(default_base_url).trim_end_matches('/');
+    let api_key = credentials.api_key.as_deref();
+
+    let mut request = transport.client().get(format!("{}/models", base_url));
+    request = match (provider, api_key) {
+        (ProviderKind::Anthropic, Some(api_key)) => request.header("x-api-key", api_key).header("anthropic-version", ANTHROPIC_VERSION),
+        (ProviderKind::OpenAi, Some(api_key)) => with_openai_account_headers(
+            request.header(header::AUTHORIZATION, format!("Bearer {}", api_key)),
+            credentials.organization.as_deref(),
+            credentials.project.as_deref(),
+        ),
+        (_, Some(api_key)) => request.header(header::AUTHORIZATION, format!("Bearer {}", api_key)),
+        (_, None) => request,
+    };
+    let response = transport.send(request).await
+        .map_err(|e| format!("Couldn't reach {}: {}", base_url, e.user_message()))?;
+    let body = match read_body(provider, response).await? {
+        Ok(body) => body,
+        Err(report) => return Ok(report),
+    };
+
+    let value: Value = serde_json::from_str(&body).map_err(|e| format!("Unexpected model list from {}: {}", base_url, e))?;
+    let available: Vec<String> = value["data"].as_array().into_iter().flatten()
+        .filter_map(|model| model["id"].as_str().map(str::to_owned))
+        .collect();
+
+    let configured = if credentials.models.is_empty() { vec![default_model.to_string()] } else { credentials.models.clone() };
+    let models: Vec<ModelAccess> = configured.into_iter().map(|model| ModelAccess {
+        accessible: available.iter().any(|listed| model_matches(&model, listed)),
+        vision: supports_vision(provider, &model),
+        model,
+    }).collect();
+    let vision_model = models.iter()
+        .find(|access| access.accessible && access.vision)
+        .map(|access| access.model.clone())
+        .or_else(|| available.iter().find(|listed| supports_vision(provider, listed)).cloned());
+    Ok(report(provider, models, vision_model))
+}
+
+/// Azure has no data plane call to list deployments, so a one token completion checks the key
+/// and the deployment at once, and says which model the deployment runs.
+async fn validate_azure(transport: &Transport, credentials: &Credentials) -> Result<ValidationReport, String> {
+    let provider = ProviderKind::AzureOpenAi;
+    let (Some(endpoint), Some(deployment)) = (credentials.base_url.as_deref(), credentials.azure_deployment.as_deref()) else {
+        return Err("Azure OpenAI needs an endpoint and a deployment".to_string());
+    };
+    let url = format!(
+        "{}/openai/deployments/{}/chat/completions?api-version={}",
+        endpoint.trim_end_matches('/'),
+        deployment,
+        credentials.azure_api_version.as_deref().unwrap_or(AZURE_DEFAULT_API_VERSION)
+    );
+    let request = transport.client()
+        .post(url)
+        .header("api-key", credentials.api_key.as_deref().unwrap_or_default())
+        .json(&json!({ "messages": [{ "role": "user", "content": "Hi" }], "max_tokens": 1 }));
+    let response = transport.send(request).await
+        .map_err(|e| format!("Couldn't reach {}: {}", endpoint, e.user_message()))?;
+
+    let model = match response.status {
+        StatusCode::NOT_FOUND => None,
+        _ => match read_body(provider, response).await? {
+            Ok(body) => Some(serde_json::from_str::<Value>(&body).ok()
+                .and_then(|value| value["model"].as_str().map(str::to_owned))
+                .unwrap_or_else(|| deployment.to_string())),
+            Err(report) => return Ok(report),
+        },
+    };
+    let vision = model.as_deref().is_some_and(|model| supports_vision(provider, model));
+    let models = vec![ModelAccess { model: deployment.to_string(), accessible: model.is_some(), vision }];
+    let vision_model = vision.then(|| deployment.to_string());
+    Ok(report(provider, models, vision_model))
+}
+
+/// The body of a successful response, or a report if the credentials were rejected.
+async fn read_body(provider: ProviderKind, response: HttpResponse) -> Result<Result<String, ValidationReport>, String> {
+    let status = response.status;
+    let headers = response.headers.clone();
+    let body = response.text().await.map_err(|e| e.user_message())?;
+    match status {
+        _ if status.is_success() => Ok(Ok(body)),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            let error = GptError::from_response(status, &headers, &body);
+            Ok(Err(ValidationReport::rejected(provider, error.user_message())))
+        }
+        _ => Err(format!("Failed to validate the credentials: HTTP {}", status)),
+    }
+}
+
+fn report(provider: ProviderKind, models: Vec<ModelAccess>, vision_model: Option<String>) -> ValidationReport {
+    let missing: Vec<&str> = models.iter().filter(|access| !access.accessible).map(|access| access.model.as_str()).collect();
+    let message = if !missing.is_empty() {
+        Some(format!("The key is valid, but can't use {}", missing.join(", ")))
+    } else if vision_model.is_none() {
+        Some("The key is valid, but none of its models can see your screen".to_string())
+    } else {
+        None
+    };
+    ValidationReport { provider: provider.as_store_value(), valid: true, message, models, vision_model }
+}
+
+/// Ollama lists `llava` as `llava:latest`, and Anthropic's `-latest` aliases aren't listed at all.
+fn model_matches(configured: &str, listed: &str) -> bool {
+    listed == configured
+        || listed.strip_suffix(":latest") == Some(configured)
+        || configured.strip_suffix("-latest").is_some_and(|family| listed.starts_with(family))
+}
+
+/// Checks the given credentials, filling in whatever is missing from the settings.
+#[tauri::command]
+pub async fn validate_credentials(app_handle: AppHandle, gpt_client: State<'_, GptClient>, credentials: Option<Credentials>) -> Result<ValidationReport, String> {
+    let credentials = credentials.unwrap_or_default().or_from_store(&app_handle);
+    validate_credentials_with(gpt_client.transport(), &credentials).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cassette::{Cassette, Chunk, Interaction};
+
+    fn response(method: &str, path: &str, status: u16, body: &str) -> Interaction {
+        Interaction {
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            chunks: vec![Chunk::new(0, body.as_bytes())],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_against_replayed_providers() {
+        let transport = Transport::replay(Cassette {
+            interactions: vec![
+                response("GET", "/v1/models", 200, r#"{"object": "list", "data": [{"id": "gpt-4o"}, {"id": "gpt-3.5-turbo"}]}"#),
+                response("GET", "/v1/models", 401, r#"{"error": {"message": "Incorrect API key provided", "code": "invalid_api_key"}}"#),
+                response("GET", "/v1/models", 500, r#"{"error": {"message": "The server had an error"}}"#),
+                response("POST", "/openai/deployments/vision/chat/completions?api-version=2023-12-01-preview", 200, r#"{"model": "gpt-4o-2024-05-13", "choices": []}"#),
+            ],
+        });
+        let openai = |models: &[&str]| Credentials {
+            provider: Some("openai".to_string()),
+            api_key: Some("sk-test".to_string()),
+            models: models.iter().map(|model| model.to_string()).collect(),
+            ..Credentials::default()
+        };
+
+        let report = validate_credentials_with(&transport, &openai(&["gpt-3.5-turbo", "gpt-4-turbo"])).await.unwrap();
+        assert!(report.valid);
+        assert_eq!(report.models.iter().map(|access| access.accessible).collect::<Vec<_>>(), vec![true, false]);
+        assert_eq!(report.vision_model.as_deref(), Some("gpt-4o"));
+        assert_eq!(report.message.as_deref(), Some("The key is valid, but can't use gpt-4-turbo"));
+
+        let report = validate_credentials_with(&transport, &openai(&[])).await.unwrap();
+        assert!(!report.valid);
+        assert!(validate_credentials_with(&transport, &openai(&[])).await.is_err());
+
+        let azure = Credentials {
+            provider: Some("azure_openai".to_string()),
+            api_key: Some("azure-key".to_string()),
+            base_url: Some("https://example.openai.azure.com/".to_string()),
+            azure_deployment: Some("vision".to_string()),
+            ..Credentials::default()
+        };
+        let report = validate_credentials_with(&transport, &azure).await.unwrap();
+        assert!(report.valid && report.message.is_none());
+        assert_eq!(report.vision_model.as_deref(), Some("vision"));
+    }
+}
@@ -1,276 +1,662 @@
-use std::env;
 use std::path::PathBuf;
 use std::time::Duration;
-use anyhow::{anyhow, Result};
-use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, Role};
-use base64::{Engine as _, engine::{general_purpose}};
-use reqwest::{Client, header};
-use serde_json::{json, Value};
+use anyhow::Result;
+use async_openai::types::Role;
+use serde_json::json;
 use tauri::{AppHandle, Manager};
+use futures::future::{Abortable, Aborted, BoxFuture};
 use futures_util::StreamExt;
-use log::{error, info};
-use tokio::{fs, time};
-use crate::stores::get_from_store;
-
-const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
-#[tauri::command]
-pub async fn check_api_key_validity(api_key: String) -> Result<bool, String> {
-    // Attempt to list models as a lightweight check
-    let client = Client::new();
-    let response = client
-        .get(format!("{}/models", OPENAI_BASE_URL))
-        .header(header::AUTHORIZATION, format!("Bearer {}", api_key))
-        .send()
-        .await;
-
-    match response {
-        Ok(resp) => {
-            if resp.status().is_success() {
-                Ok(true) // The API key is valid
-            } else if resp.status().as_u16() == 401 {
-                Ok(false) // Unauthorized, API key is not valid
-            } else {
-                // Some other error occurred
-                Err(format!("Failed to validate API key: HTTP {}", resp.status()))
-            }
-        },
-        Err(err) => Err(format!("Failed to make request: {}", err)),
+use log::{error, info, warn};
+use tokio::time;
+use crate::cassette::Transport;
+use crate::gpt_error::GptError;
+use crate::http_client::{client_from_store, HttpSettings};
+use crate::images::{history_image_turns, ImageAttachment, prune_images};
+use crate::providers::{ChatMessage, ChatProvider, ChatRequest, GenerationParams, ImageDetail, provider_from_store, StreamEvent, supports_tools, TokenUsage};
+use crate::context::{fit_to_context, KEEP_RECENT_EXCHANGES, prompt_budget, SUMMARY_THRESHOLD};
+use crate::conversation::{Conversation, ConversationMode, ConversationState};
+use crate::memory::{parse_fact_list, relevant_memories};
+use crate::personas::{PersonaLibrary, render_template};
+use crate::requests::RequestRegistry;
+use crate::stores::get_string_from_store;
+use crate::speech::Speaker;
+use crate::sse::SseDecoder;
+use crate::tools::{ToolCall, ToolCallAccumulator, ToolRegistry, ToolResult, ToolRound};
+use crate::usage::{check_spend_caps, cost_usd, estimate_prompt_tokens, record_usage, SpendCheck, UsageLedger};
+
+/// How many times the model may call tools while answering a single question
+const MAX_TOOL_ROUNDS: usize = 5;
+const MAX_RETRIES: u32 = 3;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long to wait for the response headers (the request includes a screenshot, so this is generous)
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the stream may go without sending anything before it's considered stalled
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// The user's own instructions are added to every request, so they're kept to a reasonable size
+const MAX_USER_PROMPT_CHARS: usize = 4000;
+const SUMMARY_MAX_TOKENS: u32 = 512;
+const SUMMARY_PROMPT: &str = "Summarise the conversation below between a user and an AI assistant that can see their screen. \
+Keep names, decisions, numbers, code identifiers and anything the user asked to remember, and leave out pleasantries. \
+Write it as short notes in the third person.";
+const MEMORY_MAX_TOKENS: u32 = 256;
+const MEMORY_EXTRACTION_PROMPT: &str = "Read the exchange below between a user and an AI assistant. List lasting facts about the user \
+that would help answer their future questions, such as their name, job, the tools they use and how they like answers written. \
+Only include what the user said or clearly implied, not what's on their screen or passing details of this task. \
+Reply with a JSON array of short sentences in the third person, or [] if there's nothing worth remembering.";
+
+/// The answer and everything else a provider reported while streaming it.
+#[derive(Debug, Default)]
+pub struct StreamOutcome {
+    /// The full answer, rebuilt from the content deltas
+    pub content: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<TokenUsage>,
+    /// The tool calls the model wants answered before it continues
+    pub tool_calls: Vec<ToolCall>,
+    done: bool,
+}
+
+/// Gets the content of an answer as it streams in.
+pub trait AnswerSink: Sync {
+    fn push_content(&self, content: &str) -> Result<()>;
+}
+
+/// Shows the answer in the webview and reads it out.
+struct UiSink<'a> {
+    app_handle: &'a AppHandle,
+    request_id: &'a str,
+}
+
+impl AnswerSink for UiSink<'_> {
+    fn push_content(&self, content: &str) -> Result<()> {
+        self.app_handle.emit_all("gpt_chunk_received", json!({ "request_id": self.request_id, "content": content }))?;
+        self.app_handle.state::<Speaker>().push_delta(self.request_id, content);
+        Ok(())
+    }
+}
+
+/// What the tool loop needs from the app around each round of an answer.
+pub trait RoundHooks: Sync {
+    /// Runs before every request of the answer, an error ends the answer
+    fn before_round(&self, request: &ChatRequest) -> Result<()>;
+    fn after_round(&self, request: &ChatRequest, outcome: &StreamOutcome);
+    fn run_tool<'a>(&'a self, call: &'a ToolCall) -> BoxFuture<'a, ToolResult>;
+}
+
+struct AppRoundHooks<'a> {
+    client: &'a GptClient,
+    request_id: &'a str,
+    app_handle: &'a AppHandle,
+    provider: &'a dyn ChatProvider,
+    ledger: &'a UsageLedger,
+}
+
+impl RoundHooks for AppRoundHooks<'_> {
+    /// Each round is a request of its own, and tool results (screenshots in particular) make
+    /// them more expensive, so every one is checked against the spend caps.
+    fn before_round(&self, request: &ChatRequest) -> Result<()> {
+        self.client.enforce_spend_caps(self.request_id, self.provider, request, self.ledger)
+    }
+
+    fn after_round(&self, request: &ChatRequest, outcome: &StreamOutcome) {
+        let estimated_prompt_tokens = estimate_prompt_tokens(self.provider.kind(), request);
+        record_usage(self.ledger, self.provider.kind(), self.provider.model(), estimated_prompt_tokens, &outcome.content, outcome.usage.as_ref());
+    }
+
+    fn run_tool<'a>(&'a self, call: &'a ToolCall) -> BoxFuture<'a, ToolResult> {
+        Box::pin(self.client.tools.execute(self.app_handle, call))
     }
 }
 
 #[derive(Debug)]
 pub struct GptClient {
     app_handle: AppHandle,
-    client: Client,
-    api_url: String,
+    transport: Transport,
+    tools: ToolRegistry,
 }
 
 impl GptClient {
+    /// Talks to the network through the proxy and CAs from the settings, or to a cassette when
+    /// `DERBY_RECORD_CASSETTE` / `DERBY_REPLAY_CASSETTE` is set.
     pub fn new(app_handle: AppHandle) -> Self {
+        let transport = Transport::from_env(client_from_store(&app_handle));
+        transport.set_request_timeout(HttpSettings::from_store(&app_handle).request_timeout);
         Self {
+            transport,
             app_handle,
-            client: Client::new(),
-            api_url: format!("{}/chat/completions", OPENAI_BASE_URL),
+            tools: ToolRegistry::with_default_tools(),
         }
     }
 
-    pub async fn get_gpt_response(&self, messages: Vec<ChatCompletionRequestMessage>, image_path: PathBuf, app_handle: AppHandle) -> Result<()> {
-        if self.is_testing_env() {
-            self.emit_test_events().await?;
-            return Ok(());
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Streams an answer to the UI. Every event of the request carries its `request_id`, and the
+    /// request ends with either `gpt_stream_end` or `gpt_stream_error`.
+    pub async fn get_gpt_response(&self, messages: Vec<ChatMessage>, app_handle: AppHandle) -> Result<StreamOutcome> {
+        let registry = app_handle.state::<RequestRegistry>();
+        let (request_id, abort_registration) = registry.start();
+        info!("Starting request {}", request_id);
+        self.app_handle.emit_all("gpt_stream_start", json!({ "request_id": request_id }))?;
+        let speaker = app_handle.state::<Speaker>();
+        speaker.start_answer(&app_handle, &request_id);
+
+        let answer = self.answer(&request_id, messages, app_handle.clone());
+        let result = Abortable::new(answer, abort_registration).await;
+        registry.finish(&request_id);
+
+        match result {
+            Ok(Ok(outcome)) => {
+                speaker.finish_answer(&request_id);
+                self.app_handle.emit_all("gpt_stream_end", json!({
+                    "request_id": request_id,
+                    "finish_reason": outcome.finish_reason,
+                    "usage": outcome.usage,
+                    "cancelled": false,
+                }))?;
+                Ok(outcome)
+            }
+            Ok(Err(e)) => {
+                error!("Failed to get GPT response for {}: {}", request_id, e);
+                speaker.finish_answer(&request_id);
+                self.emit_stream_error(&request_id, &e);
+                Err(e)
+            }
+            Err(Aborted) => {
+                // Dropping the answer future also dropped the reqwest stream, which closes the connection
+                info!("Request {} was cancelled", request_id);
+                speaker.cancel_answer(&request_id);
+                self.app_handle.emit_all("gpt_stream_end", json!({
+                    "request_id": request_id,
+                    "cancelled": true,
+                }))?;
+                Err(GptError::Cancelled.into())
+            }
         }
+    }
 
-        let base64_string = self.encode_image(image_path).await?;
-        let json_messages = self.prepare_messages_for_payload(messages, &base64_string);
-        let payload = self.build_payload(json_messages)?;
-        // print the first 100 characters of the payload
-        info!("Payload first 100 chars: {}", payload.to_string().chars().take(100).collect::<String>());
-        self.send_request_and_emit_events(payload, app_handle).await?;
-        Ok(())
+    async fn answer(&self, request_id: &str, mut messages: Vec<ChatMessage>, app_handle: AppHandle) -> Result<StreamOutcome> {
+        // The provider is looked up per request so that changes on the settings page apply to the next question
+        let provider = provider_from_store(&app_handle).map_err(|e| {
+            error!("Failed to set up chat provider: {}", e);
+            e
+        })?;
+        info!("Sending chat request to {}", provider.kind().as_store_value());
+
+        prune_images(&mut messages, history_image_turns(&app_handle));
+        // Vision models such as gpt-4-vision-preview and llava reject requests that offer tools
+        let tools = if supports_tools(provider.kind(), provider.model()) {
+            self.tools.specs(&app_handle)
+        } else {
+            info!("{} doesn't support tools, asking without them", provider.model());
+            Vec::new()
+        };
+        let request = ChatRequest {
+            messages,
+            image_detail: ImageDetail::from_store(&app_handle),
+            params: GenerationParams::from_store(&app_handle, provider.as_ref())?,
+            tools,
+            tool_rounds: Vec::new(),
+        };
+
+        let ledger = app_handle.state::<UsageLedger>();
+        let sink = UiSink { app_handle: &app_handle, request_id };
+        let hooks = AppRoundHooks { client: self, request_id, app_handle: &app_handle, provider: provider.as_ref(), ledger: &ledger };
+        answer_with_tools(&self.transport, Some(&sink), provider.as_ref(), request, &hooks).await
     }
 
-    async fn encode_image(&self, image_path: PathBuf) -> Result<String> {
-        let buffer = fs::read(&image_path).await?;
-        let b64 = general_purpose::STANDARD.encode(&buffer);
-        Ok(b64)
-    }
-
-    fn prepare_messages_for_payload(&self, messages: Vec<ChatCompletionRequestMessage>, base64_string: &str) -> Vec<Value> {
-        messages.into_iter().map(|msg| {
-            let content = match msg.role {
-                Role::User => json!([
-                    {
-                        "type": "text",
-                        "text": msg.content.unwrap_or_default(),
-                    },
-                    {
-                        "type": "image_url",
-                        "image_url": {
-                            "url": format!("data:image/jpeg;base64,{}", base64_string)
-                        }
-                    }
-                ]),
-                Role::Assistant | Role::System => json!([{
-                    "type": "text",
-                    "text": msg.content.unwrap_or_default(),
-                }]),
-                _ => json!({
-                    "error": "Role not specified"
-                })
-            };
-
-            json!({
-                "role": msg.role.to_string(),
-                "content": content,
-            })
-        }).collect()
-    }
-
-    fn build_payload(&self, json_messages: Vec<Value>) -> Result<Value> {
-        Ok(json!({
-            "model": "gpt-4-vision-preview",
-            "messages": json_messages,
-            "stream": true,
-            "max_tokens": 150,
-        }))
-    }
-
-    async fn send_request_and_emit_events(&self, payload: Value, app_handle: AppHandle) -> Result<()> {
-        let openai_api_key = get_from_store(&app_handle, "api_token")
-            .ok_or_else(|| {
-                error!("OpenAI API key not found");
-                anyhow!("OpenAI API key not found")
-            })?
-            .to_string().replace("\"", "");
-
-        let response = self.client
-            .post(&self.api_url)
-            .header(header::CONTENT_TYPE, "application/json")
-            .header(header::AUTHORIZATION, format!("Bearer {}", openai_api_key))
-            .json(&payload)
-            .send()
-            .await?;
-
-        let mut stream = response.bytes_stream();
-        let mut json_parser = JSONBufferParser::new();
-
-        self.app_handle.emit_all("gpt_stream_start", json!({ "status": "start" }))?;
-        let mut first_chunk = true;
-        while let Some(item) = stream.next().await {
-            if first_chunk {
-                info!("First chunk: {:?}", item);
-                first_chunk = false;
-            }
-            let chunk = item?;
-            let chunk_str = String::from_utf8(chunk.to_vec())?;
+    /// Asks `question`, with any number of images attached, as the next turn of the shared conversation
+    /// (or the first turn of a new one), then records the question and the answer rebuilt from the
+    /// streamed deltas in the thread. `persona_id` overrides the default persona for this question.
+    pub async fn ask_in_conversation(&self, conversation: &ConversationState, mode: ConversationMode, question: String, image_paths: Vec<PathBuf>, persona_id: Option<&str>, app_handle: AppHandle) -> Result<String> {
+        let mut images = Vec::new();
+        for path in &image_paths {
+            images.push(ImageAttachment::from_file(path).await?);
+        }
+        let memories = relevant_memories(&app_handle, self.transport.clone(), &question).await;
+        let question = ChatMessage::new(Role::User, question).with_images(images);
+
+        let system_messages = messages_setup(&app_handle, persona_id, &memories).await;
+
+        let mut thread = conversation.begin(mode);
+        match self.summarise_if_needed(conversation, &thread, &app_handle).await {
+            Ok(true) => thread = conversation.begin(ConversationMode::Continue),
+            Ok(false) => {}
+            // Not fatal, the oldest turns will be trimmed instead
+            Err(e) => warn!("Failed to summarise the conversation: {}", e),
+        }
+
+        let messages = thread.messages_with_question(system_messages, question.clone());
+        let outcome = self.get_gpt_response(messages, app_handle).await?;
+        conversation.record_exchange(question, outcome.content.clone());
+        Ok(outcome.content)
+    }
+
+    /// Once the thread takes up too much of the model's context, its older turns are replaced with a
+    /// summary written by the model, unless that would go over a spend cap. Returns whether the
+    /// thread was summarised.
+    async fn summarise_if_needed(&self, conversation: &ConversationState, thread: &Conversation, app_handle: &AppHandle) -> Result<bool> {
+        let provider = provider_from_store(app_handle)?;
+        let params = GenerationParams::from_store(app_handle, provider.as_ref())?;
+        let budget = prompt_budget(provider.kind(), provider.model(), params.max_tokens);
+        if (thread.estimated_tokens() as f64) < budget as f64 * SUMMARY_THRESHOLD {
+            return Ok(false);
+        }
+        let turns = thread.turns_to_summarise(KEEP_RECENT_EXCHANGES);
+        if turns.is_empty() {
+            return Ok(false);
+        }
+        info!("Conversation is about {} tokens, summarising {} messages", thread.estimated_tokens(), turns.len());
 
-            // Append the chunk to the JSON buffer and process any complete JSON objects
-            json_parser.append(&chunk_str);
-            for content in json_parser.extract_content() {
-                self.app_handle.emit_all("gpt_chunk_received", content)?;
+        let mut transcript = String::new();
+        if let Some(summary) = thread.summary() {
+            transcript.push_str(&format!("Summary of the earlier conversation:\n{}\n\n", summary));
+        }
+        for msg in turns {
+            let speaker = if msg.role == Role::User { "User" } else { "Assistant" };
+            transcript.push_str(&format!("{}: {}\n\n", speaker, msg.content));
+        }
+        let request = ChatRequest {
+            messages: vec![ChatMessage::new(Role::System, SUMMARY_PROMPT), ChatMessage::new(Role::User, transcript)],
+            image_detail: ImageDetail::Low,
+            params: GenerationParams { max_tokens: SUMMARY_MAX_TOKENS, temperature: None, top_p: None, stop: Vec::new() },
+            tools: Vec::new(),
+            tool_rounds: Vec::new(),
+        };
+
+        // Over a cap the thread is trimmed instead, which costs nothing
+        let ledger = app_handle.state::<UsageLedger>();
+        match check_request_cost(app_handle, provider.as_ref(), &request, &ledger) {
+            SpendCheck::Ok => {}
+            SpendCheck::Warn(message) => warn!("{}", message),
+            SpendCheck::Block(message) => {
+                info!("Not summarising the conversation: {}", message);
+                return Ok(false);
             }
         }
-        Ok(())
+
+        // The summary isn't part of the answer, so it's requested without a sink and nothing is emitted
+        let outcome = send_request(&self.transport, None, provider.as_ref(), &request).await?;
+        record_usage(&ledger, provider.kind(), provider.model(), estimate_prompt_tokens(provider.kind(), &request), &outcome.content, outcome.usage.as_ref());
+
+        let summary = outcome.content.trim();
+        if summary.is_empty() {
+            return Ok(false);
+        }
+        conversation.apply_summary(turns, summary.to_string());
+        Ok(true)
     }
 
-    async fn emit_test_events(&self) -> Result<()> {
-        let responses = self.read_mocked_responses("openai_response.txt").await?;
-        for response in responses {
-            time::sleep(Duration::from_millis(100)).await;
-            self.app_handle.emit_all("gpt_chunk_received", response)?;
+    /// Asks the model for lasting facts about the user in a finished exchange. Nothing is emitted
+    /// or stored, the user confirms the facts first. Fails when the request would go over a spend cap.
+    pub async fn extract_facts(&self, question: &str, answer: &str, app_handle: &AppHandle) -> Result<Vec<String>> {
+        let provider = provider_from_store(app_handle)?;
+        let transcript = format!("User: {}\n\nAssistant: {}", question, answer);
+        let request = ChatRequest {
+            messages: vec![ChatMessage::new(Role::System, MEMORY_EXTRACTION_PROMPT), ChatMessage::new(Role::User, transcript)],
+            image_detail: ImageDetail::Low,
+            params: GenerationParams { max_tokens: MEMORY_MAX_TOKENS, temperature: Some(0.0), top_p: None, stop: Vec::new() },
+            tools: Vec::new(),
+            tool_rounds: Vec::new(),
+        };
+
+        let ledger = app_handle.state::<UsageLedger>();
+        match check_request_cost(app_handle, provider.as_ref(), &request, &ledger) {
+            SpendCheck::Ok => {}
+            SpendCheck::Warn(message) => warn!("{}", message),
+            SpendCheck::Block(message) => return Err(GptError::SpendCapReached(message).into()),
         }
-        Ok(())
+
+        let outcome = send_request(&self.transport, None, provider.as_ref(), &request).await?;
+        record_usage(&ledger, provider.kind(), provider.model(), estimate_prompt_tokens(provider.kind(), &request), &outcome.content, outcome.usage.as_ref());
+        Ok(parse_fact_list(&outcome.content))
     }
 
-    async fn read_mocked_responses(&self, file_path: &str) -> Result<Vec<String>> {
-        let buffer = fs::read(file_path).await?;
-        Ok(buffer
-            .split_inclusive(|&x| x == b' ')
-            .filter_map(|bytes| String::from_utf8(bytes.to_vec()).ok())
-            .collect())
+    /// Blocks the request, or warns about it, if its worst case cost would go over a spend cap.
+    fn enforce_spend_caps(&self, request_id: &str, provider: &dyn ChatProvider, request: &ChatRequest, ledger: &UsageLedger) -> Result<()> {
+        match check_request_cost(&self.app_handle, provider, request, ledger) {
+            SpendCheck::Ok => Ok(()),
+            SpendCheck::Warn(message) => {
+                warn!("{}", message);
+                self.app_handle.emit_all("gpt_spend_warning", json!({ "request_id": request_id, "message": message }))?;
+                Ok(())
+            }
+            SpendCheck::Block(message) => Err(GptError::SpendCapReached(message).into()),
+        }
     }
 
-    fn is_testing_env(&self) -> bool {
-        let is_testing_env = env::var("TESTING_ENV").map(|val| val == "true").unwrap_or(false);
-        info!("Running in testing environment: {}", is_testing_env);
-        is_testing_env
+    fn emit_stream_error(&self, request_id: &str, error: &anyhow::Error) {
+        let payload = match error.downcast_ref::<GptError>() {
+            Some(gpt_error) => json!({ "request_id": request_id, "kind": gpt_error.kind(), "message": gpt_error.user_message() }),
+            None => json!({ "request_id": request_id, "kind": "other", "message": format!("Something went wrong: {}", error) }),
+        };
+        if let Err(e) = self.app_handle.emit_all("gpt_stream_error", payload) {
+            error!("Failed to emit gpt_stream_error: {}", e);
+        }
     }
 }
 
-pub fn create_chat_completion_request_msg(content: String, role: Role) -> ChatCompletionRequestMessage {
-    ChatCompletionRequestMessageArgs::default()
-        .content(content)
-        .role(role)
-        .build()
-        .unwrap()
+/// Keeps answering the model's tool calls until it produces a final answer, or until it has
+/// called tools `MAX_TOOL_ROUNDS` times.
+pub async fn answer_with_tools(transport: &Transport, sink: Option<&dyn AnswerSink>, provider: &dyn ChatProvider, mut request: ChatRequest, hooks: &dyn RoundHooks) -> Result<StreamOutcome> {
+    loop {
+        // Tool results make the request grow, so it's checked against the context before every round
+        fit_to_context(provider.kind(), provider.model(), &mut request)?;
+        hooks.before_round(&request)?;
+        let outcome = send_request(transport, sink, provider, &request).await?;
+        hooks.after_round(&request, &outcome);
+
+        if outcome.tool_calls.is_empty() {
+            return Ok(outcome);
+        }
+        if request.tool_rounds.len() >= MAX_TOOL_ROUNDS {
+            warn!("Giving up after {} rounds of tool calls", MAX_TOOL_ROUNDS);
+            return Ok(outcome);
+        }
+
+        let mut results = Vec::new();
+        for call in &outcome.tool_calls {
+            info!("Model requested tool call {} ({})", call.name, call.id);
+            results.push(hooks.run_tool(call).await);
+        }
+        request.tool_rounds.push(ToolRound {
+            assistant_text: outcome.content,
+            calls: outcome.tool_calls,
+            results,
+        });
+    }
 }
 
+/// Sends the request, retrying transient failures. The content goes to `sink` as it streams in,
+/// unless there's none (for requests made in the background).
+pub async fn send_request(transport: &Transport, sink: Option<&dyn AnswerSink>, provider: &dyn ChatProvider, request: &ChatRequest) -> Result<StreamOutcome> {
+    let mut attempt = 0;
+    loop {
+        let mut outcome = StreamOutcome::default();
+        let result = stream_response(transport, sink, provider, request, &mut outcome).await;
+        let Err(e) = result else {
+            info!("Stream finished, finish reason: {:?}, usage: {:?}", outcome.finish_reason, outcome.usage);
+            return Ok(outcome);
+        };
+
+        // Once part of the answer is on screen a retry would repeat it, so only failures before that are retried
+        let retryable = e.downcast_ref::<GptError>().map_or(false, GptError::is_retryable);
+        if !retryable || !outcome.content.is_empty() || attempt >= MAX_RETRIES {
+            return Err(e);
+        }
+        let delay = e.downcast_ref::<GptError>()
+            .and_then(GptError::retry_after)
+            .unwrap_or_else(|| backoff_delay(attempt))
+            .min(MAX_RETRY_DELAY);
+        warn!("Chat request failed ({}), retrying in {:?}", e, delay);
+        time::sleep(delay).await;
+        attempt += 1;
+    }
+}
 
-pub fn messages_setup() -> Vec<ChatCompletionRequestMessage> {
-    let system_message_content = "This is an AI macos app where the user questions/tasks the AI via speech-to-text.\
-    The app also takes a screenshot of the user's screen and sends it to the AI so that the AI can use it to answer the user's question.\
-    Don't refer to the 'screenshot' in your response, instead call it their 'screen' or 'desktop'.\
-    Sometimes the user might just say something that is not related to the screenshot. In this case, just ignore the screenshot and respond normally.\
-     Since the user uses speech-to-text to communicate, so some of their messages may be incorrect - make assumptions (on incorrect words etc.) based on this.\
-     In The user will be unable to respond to you after you send a message, so do not ask any questions or ask for clarification.\
-      Ensure that your output is just the output they requested - do not ask any follow up questions or include any extra text.";
-    let system_message = create_chat_completion_request_msg(system_message_content.to_string(), Role::System);
+async fn stream_response(transport: &Transport, sink: Option<&dyn AnswerSink>, provider: &dyn ChatProvider, request: &ChatRequest, outcome: &mut StreamOutcome) -> Result<()> {
+    let mut http_request = provider.build_request(&transport.client(), request)?;
+    if let Some(timeout) = transport.request_timeout() {
+        http_request = http_request.timeout(timeout);
+    }
+    let response = time::timeout(RESPONSE_TIMEOUT, transport.send(http_request))
+        .await
+        .map_err(|_| GptError::Stalled(RESPONSE_TIMEOUT))??;
+
+    if !response.status.is_success() {
+        let status = response.status;
+        let headers = response.headers.clone();
+        let body = time::timeout(RESPONSE_TIMEOUT, response.text()).await
+            .ok()
+            .and_then(|body| body.ok())
+            .unwrap_or_default();
+        return Err(GptError::from_response(status, &headers, &body).into());
+    }
 
-    // let user_prompt_content = get_from_store(handle, "userPrompt").unwrap_or("".to_string());
-    // let user_prompt_message = create_chat_completion_request_msg("user_prompt_content".to_string(), Role::System);
+    let mut stream = response.body;
+    let mut decoder = SseDecoder::new();
+    let mut tool_calls = ToolCallAccumulator::default();
+
+    let mut first_chunk = true;
+    loop {
+        // A connection can stay open without sending anything, so each chunk has to arrive within the idle timeout
+        let item = match time::timeout(STREAM_IDLE_TIMEOUT, stream.next()).await {
+            Ok(Some(item)) => item,
+            Ok(None) => break,
+            Err(_) => return Err(GptError::Stalled(STREAM_IDLE_TIMEOUT).into()),
+        };
+        if first_chunk {
+            info!("First chunk: {:?}", item);
+            first_chunk = false;
+        }
+        let chunk = item?;
 
-    return vec![system_message]
+        // The decoder works on raw bytes, so a multi-byte character split across chunks is fine
+        for event in decoder.push(&chunk) {
+            if handle_stream_events(sink, provider.parse_event(&event), outcome, &mut tool_calls)? {
+                break;
+            }
+        }
+        if outcome.done {
+            break;
+        }
+    }
+    if let Some(event) = decoder.finish().filter(|_| !outcome.done) {
+        handle_stream_events(sink, provider.parse_event(&event), outcome, &mut tool_calls)?;
+    }
+    if !outcome.done && outcome.finish_reason.is_none() {
+        return Err(GptError::Network("The stream ended before the answer was complete".to_string()).into());
+    }
+    outcome.tool_calls = tool_calls.finish();
+    Ok(())
 }
 
+/// Hands the content deltas to `sink` and records everything else in `outcome`. Returns true once the provider has signalled the end of the stream.
+fn handle_stream_events(sink: Option<&dyn AnswerSink>, events: Vec<StreamEvent>, outcome: &mut StreamOutcome, tool_calls: &mut ToolCallAccumulator) -> Result<bool> {
+    for event in events {
+        match event {
+            StreamEvent::Content(content) => {
+                outcome.content.push_str(&content);
+                if let Some(sink) = sink {
+                    sink.push_content(&content)?;
+                }
+            }
+            StreamEvent::ToolCallDelta { index, id, name, arguments } => {
+                tool_calls.push(index, id, name, &arguments);
+            }
+            StreamEvent::Finish(reason) => outcome.finish_reason = Some(reason),
+            StreamEvent::Usage(usage) => outcome.usage.get_or_insert_with(TokenUsage::default).merge(usage),
+            StreamEvent::Error(e) => return Err(e.into()),
+            StreamEvent::Done => outcome.done = true,
+        }
+    }
+    Ok(outcome.done)
+}
 
-// JSONBufferParser helps in buffering chunks and extracting complete JSON objects.
-struct JSONBufferParser {
-    buffer: String,
+/// Checks the spend caps against the worst case cost of `request`.
+fn check_request_cost(app_handle: &AppHandle, provider: &dyn ChatProvider, request: &ChatRequest, ledger: &UsageLedger) -> SpendCheck {
+    let estimated_prompt_tokens = estimate_prompt_tokens(provider.kind(), request);
+    let worst_case_cost = cost_usd(provider.kind(), provider.model(), estimated_prompt_tokens, request.params.max_tokens)
+        .unwrap_or_default();
+    info!("Estimated {} prompt tokens, worst case cost ${:.4}", estimated_prompt_tokens, worst_case_cost);
+    check_spend_caps(app_handle, ledger, worst_case_cost)
 }
 
-impl JSONBufferParser {
-    pub fn new() -> Self {
-        Self {
-            buffer: String::new(),
+/// Exponential backoff for retries without a `Retry-After`: 1s, 2s, 4s, ...
+fn backoff_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5))
+}
+
+/// The system message: the template of the chosen persona (or the default one) with its variables
+/// filled in, followed by the extra instructions from the settings page (`userPrompt`) and the
+/// remembered facts relevant to the question.
+pub async fn messages_setup(handle: &AppHandle, persona_id: Option<&str>, memories: &[String]) -> Vec<ChatMessage> {
+    let persona = handle.state::<PersonaLibrary>().get_or_default(persona_id);
+    let mut system_message_content = match persona {
+        Some(persona) => render_template(handle, &persona.template).await,
+        None => String::new(),
+    };
+
+    let user_prompt = get_string_from_store(handle, "userPrompt").unwrap_or_default();
+    if !user_prompt.trim().is_empty() {
+        let mut user_prompt = user_prompt.trim().to_string();
+        if user_prompt.chars().count() > MAX_USER_PROMPT_CHARS {
+            warn!("User prompt is longer than {} characters, truncating it", MAX_USER_PROMPT_CHARS);
+            user_prompt = user_prompt.chars().take(MAX_USER_PROMPT_CHARS).collect();
         }
+        system_message_content.push_str(&format!("\n\nFollow these additional instructions from the user:\n{}", user_prompt));
     }
 
-    fn append(&mut self, chunk: &str) {
-        self.buffer.push_str(chunk);
+    if !memories.is_empty() {
+        let facts: Vec<String> = memories.iter().map(|fact| format!("- {}", fact)).collect();
+        system_message_content.push_str(&format!("\n\nThings you know about the user from earlier conversations:\n{}", facts.join("\n")));
     }
 
-    fn extract_content(&mut self) -> Vec<String> {
-        let mut contents = Vec::new();
-        loop {
-            match self.find_json_object_boundaries() {
-                Some((start, end)) => {
-                    let json_str = self.buffer[start..=end].to_owned();
-                    // Attempt to parse the JSON object and extract the content.
-                    if let Some(content) = extract_content_from_json(&json_str) {
-                        contents.push(content);
-                    }
-                    self.buffer.drain(..=end);
-                }
-                None => break,
+    vec![ChatMessage::new(Role::System, system_message_content)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::cassette::{Cassette, Chunk, Interaction};
+    use crate::providers::{OPENAI_BASE_URL, OpenAiProvider};
+    use crate::tools::ToolSpec;
+
+    #[derive(Default)]
+    struct TestHooks {
+        rounds: Mutex<Vec<usize>>,
+        tool_calls: Mutex<Vec<ToolCall>>,
+        /// Acts as if a spend cap is reached after this many rounds of tool calls
+        blocked_after: Option<usize>,
+    }
+
+    impl RoundHooks for TestHooks {
+        fn before_round(&self, request: &ChatRequest) -> Result<()> {
+            self.rounds.lock().unwrap().push(request.tool_rounds.len());
+            if self.blocked_after == Some(request.tool_rounds.len()) {
+                return Err(GptError::SpendCapReached("Daily cap reached".to_string()).into());
             }
+            Ok(())
         }
-        contents
-    }
-
-    fn find_json_object_boundaries(&self) -> Option<(usize, usize)> {
-        let mut depth = 0;
-        let mut start_index = None;
-        for (i, ch) in self.buffer.char_indices() {
-            match ch {
-                '{' => {
-                    if depth == 0 {
-                        start_index = Some(i);
-                    }
-                    depth += 1;
-                }
-                '}' => {
-                    depth -= 1;
-                    if depth == 0 && start_index.is_some() {
-                        return Some((start_index.unwrap(), i));
-                    }
-                }
-                _ => {}
-            }
+
+        fn after_round(&self, _request: &ChatRequest, _outcome: &StreamOutcome) {}
+
+        fn run_tool<'a>(&'a self, call: &'a ToolCall) -> BoxFuture<'a, ToolResult> {
+            self.tool_calls.lock().unwrap().push(call.clone());
+            Box::pin(async move {
+                ToolResult { call_id: call.id.clone(), content: "Took a new screenshot".to_string(), is_error: false, image: None }
+            })
         }
-        None
     }
-}
 
-fn extract_content_from_json(json_str: &str) -> Option<String> {
-    let value: serde_json::Result<Value> = serde_json::from_str(json_str);
+    fn chat_interaction(events: &[&str]) -> Interaction {
+        Interaction {
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            status: 200,
+            headers: vec![("content-type".to_string(), "text/event-stream".to_string())],
+            chunks: events.iter().map(|event| Chunk::new(0, format!("data: {}\n\n", event).as_bytes())).collect(),
+        }
+    }
+
+    fn test_request() -> ChatRequest {
+        ChatRequest {
+            messages: vec![ChatMessage::new(Role::User, "What's on my screen now?")],
+            image_detail: ImageDetail::Low,
+            params: GenerationParams { max_tokens: 256, temperature: None, top_p: None, stop: Vec::new() },
+            tools: vec![ToolSpec {
+                name: "take_screenshot".to_string(),
+                description: "Take a fresh screenshot".to_string(),
+                parameters: json!({ "type": "object", "properties": {} }),
+            }],
+            tool_rounds: Vec::new(),
+        }
+    }
+
+    /// The model asking for a screenshot, the call streamed in pieces and without an id.
+    fn tool_call_round() -> Interaction {
+        chat_interaction(&[
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"function":{"name":"take_","arguments":""}}]},"finish_reason":null}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"function":{"name":"screenshot","arguments":"{"}}]},"finish_reason":null}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"function":{"arguments":"}"}}]},"finish_reason":null}]}"#,
+            r#"{"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#,
+            "[DONE]",
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_gives_up_after_max_rounds() {
+        let transport = Transport::replay(Cassette { interactions: vec![tool_call_round(); MAX_TOOL_ROUNDS + 1] });
+        let provider = OpenAiProvider::new(OPENAI_BASE_URL.to_string(), "test-key".to_string(), "gpt-4o".to_string());
+        let hooks = TestHooks::default();
+
+        let outcome = answer_with_tools(&transport, None, &provider, test_request(), &hooks).await.unwrap();
+
+        let expected_call = ToolCall { id: "call_1".to_string(), name: "take_screenshot".to_string(), arguments: "{}".to_string() };
+        assert_eq!(outcome.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(outcome.tool_calls, vec![expected_call.clone()]);
+        // Each round sends the results of all the previous ones
+        assert_eq!(*hooks.rounds.lock().unwrap(), (0..=MAX_TOOL_ROUNDS).collect::<Vec<_>>());
+        assert_eq!(*hooks.tool_calls.lock().unwrap(), vec![expected_call; MAX_TOOL_ROUNDS]);
+    }
 
-    if let Ok(val) = value {
-        if let Some(content) = val["choices"].get(0)
-            .and_then(|choice| choice["delta"].get("content"))
-            .and_then(|content| content.as_str()) {
-            return Some(content.to_owned());
+    #[tokio::test]
+    async fn test_spend_caps_are_checked_before_every_round() {
+        let transport = Transport::replay(Cassette { interactions: vec![tool_call_round(); MAX_TOOL_ROUNDS + 1] });
+        let provider = OpenAiProvider::new(OPENAI_BASE_URL.to_string(), "test-key".to_string(), "gpt-4o".to_string());
+        let hooks = TestHooks { blocked_after: Some(1), ..TestHooks::default() };
+
+        let error = answer_with_tools(&transport, None, &provider, test_request(), &hooks).await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<GptError>(), Some(GptError::SpendCapReached(_))));
+        assert_eq!(*hooks.rounds.lock().unwrap(), vec![0, 1]);
+        assert_eq!(hooks.tool_calls.lock().unwrap().len(), 1);
+    }
+
+    #[derive(Default)]
+    struct CollectingSink {
+        deltas: Mutex<Vec<String>>,
+    }
+
+    impl AnswerSink for CollectingSink {
+        fn push_content(&self, content: &str) -> Result<()> {
+            self.deltas.lock().unwrap().push(content.to_string());
+            Ok(())
         }
     }
 
-    None
-}
\ No newline at end of file
+    #[tokio::test(start_paused = true)]
+    async fn test_replayed_chat_completion() {
+        let transport = Transport::replay(Cassette {
+            interactions: vec![
+                // Retried, and never reaches the sink
+                Interaction {
+                    method: "POST".to_string(),
+                    path: "/v1/chat/completions".to_string(),
+                    status: 503,
+                    headers: Vec::new(),
+                    chunks: vec![Chunk::new(0, br#"{"error":{"message":"Overloaded"}}"#)],
+                },
+                chat_interaction(&[
+                    r#"{"choices":[{"delta":{"content":"Your editor"},"finish_reason":null}]}"#,
+                    r#"{"choices":[{"delta":{"content":" is open."},"finish_reason":null}]}"#,
+                    r#"{"choices":[{"delta":{},"finish_reason":"stop"}]}"#,
+                    r#"{"choices":[],"usage":{"prompt_tokens":812,"completion_tokens":5}}"#,
+                    "[DONE]",
+                ]),
+            ],
+        });
+        let provider = OpenAiProvider::new(OPENAI_BASE_URL.to_string(), "test-key".to_string(), "gpt-4o".to_string());
+        let sink = CollectingSink::default();
+
+        let outcome = send_request(&transport, Some(&sink), &provider, &test_request()).await.unwrap();
+
+        assert_eq!(*sink.deltas.lock().unwrap(), vec!["Your editor", " is open."]);
+        assert_eq!(outcome.content, "Your editor is open.");
+        assert_eq!(outcome.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(outcome.usage, Some(TokenUsage { prompt_tokens: Some(812), completion_tokens: Some(5) }));
+        assert!(outcome.tool_calls.is_empty());
+    }
+}
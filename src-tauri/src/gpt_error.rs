@@ -0,0 +1,236 @@
+use std::fmt;
+use std::time::Duration;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+/// The longest `Retry-After` taken at its word
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Why a chat request failed, classified from the HTTP status and the provider's error body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GptError {
+    Auth(String),
+    RateLimited { message: String, retry_after: Option<Duration> },
+    QuotaExceeded(String),
+    ContextLength(String),
+    Server { status: u16, message: String },
+    BadRequest { status: u16, message: String },
+    Network(String),
+    /// No data arrived on the stream for the given time
+    Stalled(Duration),
+    /// The request was cancelled by the user or replaced by a newer one
+    Cancelled,
+    /// The request wasn't sent because it would go over a configured spend cap
+    SpendCapReached(String),
+    /// The model settings aren't valid for the selected provider
+    InvalidSettings(String),
+}
+
+impl GptError {
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let parsed: Option<Value> = serde_json::from_str(body).ok();
+        let (error_type, message) = match &parsed {
+            Some(value) => error_type_and_message(value),
+            None => (String::new(), body.trim().to_string()),
+        };
+        let message = if message.is_empty() { status.to_string() } else { message };
+
+        if is_context_length_error(&error_type, &message) {
+            return Self::ContextLength(message);
+        }
+        match status.as_u16() {
+            401 | 403 => Self::Auth(message),
+            402 => Self::QuotaExceeded(message),
+            // OpenAI uses 429 both for rate limits and for an exhausted quota, only the latter won't go away by waiting
+            429 if error_type == "insufficient_quota" => Self::QuotaExceeded(message),
+            429 => Self::RateLimited { message, retry_after: parse_retry_after(headers) },
+            // Anthropic reports overload as 529
+            500..=599 => Self::Server { status: status.as_u16(), message },
+            status => Self::BadRequest { status, message },
+        }
+    }
+
+    /// Classifies an error object sent as an event in the middle of an otherwise successful stream.
+    pub fn from_stream_error(value: &Value) -> Self {
+        let (error_type, message) = error_type_and_message(value);
+        if is_context_length_error(&error_type, &message) {
+            return Self::ContextLength(message);
+        }
+        match error_type.as_str() {
+            "authentication_error" | "permission_error" | "invalid_api_key" => Self::Auth(message),
+            "rate_limit_error" | "rate_limit_exceeded" => Self::RateLimited { message, retry_after: None },
+            "insufficient_quota" => Self::QuotaExceeded(message),
+            "invalid_request_error" => Self::BadRequest { status: 400, message },
+            _ => Self::Server { status: 500, message },
+        }
+    }
+
+    pub fn from_reqwest(err: &reqwest::Error) -> Self {
+        Self::Network(err.to_string())
+    }
+
+    /// Transient failures that are worth retrying as long as nothing has been streamed to the UI yet.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::RateLimited { .. } | Self::Server { .. } | Self::Network(_) | Self::Stalled(_))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Auth(_) => "auth",
+            Self::RateLimited { .. } => "rate_limit",
+            Self::QuotaExceeded(_) => "quota",
+            Self::ContextLength(_) => "context_length",
+            Self::Server { .. } => "server",
+            Self::BadRequest { .. } => "bad_request",
+            Self::Network(_) => "network",
+            Self::Stalled(_) => "stalled",
+            Self::Cancelled => "cancelled",
+            Self::SpendCapReached(_) => "spend_cap",
+            Self::InvalidSettings(_) => "settings",
+        }
+    }
+
+    /// A message for the transcription window, the details only go to the log.
+    pub fn user_message(&self) -> String {
+        match self {
+            Self::Auth(_) => "Your API key was rejected. Check it on the settings page.".to_string(),
+            Self::RateLimited { .. } => "The AI service is rate limiting requests. Please try again in a moment.".to_string(),
+            Self::QuotaExceeded(_) => "Your API account has run out of credits or hit its usage limit.".to_string(),
+            Self::ContextLength(_) => "This conversation has become too long. Start a new conversation and ask again.".to_string(),
+            Self::Server { .. } => "The AI service is having problems right now. Please try again later.".to_string(),
+            Self::BadRequest { message, .. } => format!("The AI service couldn't handle this request: {}", message),
+            Self::Network(_) => "Couldn't reach the AI service. Check your internet connection.".to_string(),
+            Self::Stalled(_) => "The answer stopped arriving. Please try again.".to_string(),
+            Self::Cancelled => "The answer was cancelled.".to_string(),
+            Self::SpendCapReached(message) => message.clone(),
+            Self::InvalidSettings(message) => format!("Check your model settings: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for GptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth(message) => write!(f, "Authentication failed: {}", message),
+            Self::RateLimited { message, retry_after } => write!(f, "Rate limited (retry after {:?}): {}", retry_after, message),
+            Self::QuotaExceeded(message) => write!(f, "Quota exceeded: {}", message),
+            Self::ContextLength(message) => write!(f, "Context length exceeded: {}", message),
+            Self::Server { status, message } => write!(f, "Server error (HTTP {}): {}", status, message),
+            Self::BadRequest { status, message } => write!(f, "Request rejected (HTTP {}): {}", status, message),
+            Self::Network(message) => write!(f, "Network error: {}", message),
+            Self::Stalled(idle) => write!(f, "Stream stalled, no data for {:?}", idle),
+            Self::Cancelled => write!(f, "Request cancelled"),
+            Self::SpendCapReached(message) => write!(f, "Spend cap reached: {}", message),
+            Self::InvalidSettings(message) => write!(f, "Invalid model settings: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for GptError {}
+
+/// Both OpenAI (`{"error": {"type", "code", "message"}}`) and Anthropic (`{"type": "error", "error": {"type", "message"}}`)
+/// nest the details under `error`. OpenAI puts the more specific reason in `code`.
+fn error_type_and_message(value: &Value) -> (String, String) {
+    let error = if value["error"].is_object() { &value["error"] } else { value };
+    let error_type = error["code"].as_str()
+        .or(error["type"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    let message = error["message"].as_str()
+        .or(value["error"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    (error_type, message)
+}
+
+fn is_context_length_error(error_type: &str, message: &str) -> bool {
+    let message = message.to_lowercase();
+    error_type == "context_length_exceeded"
+        || message.contains("maximum context length")
+        || message.contains("prompt is too long")
+        || message.contains("context window")
+}
+
+/// `Retry-After` can be a number of seconds or an HTTP date. OpenAI additionally sends `retry-after-ms`.
+/// Values that aren't a duration (e.g. `inf`) are ignored, and long waits are capped.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(ms) = headers.get("retry-after-ms").and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<f64>().ok()) {
+        return retry_after_seconds(ms / 1000.0);
+    }
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return retry_after_seconds(seconds);
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(wait.max(0) as u64).min(MAX_RETRY_AFTER))
+}
+
+fn retry_after_seconds(seconds: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(seconds.max(0.0)).ok().map(|wait| wait.min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderValue, RETRY_AFTER};
+
+    #[test]
+    fn test_classifies_openai_errors() {
+        let headers = HeaderMap::new();
+        let quota = r#"{"error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "code": "insufficient_quota"}}"#;
+        assert!(matches!(GptError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, quota), GptError::QuotaExceeded(_)));
+
+        let context = r#"{"error": {"message": "This model's maximum context length is 8192 tokens", "type": "invalid_request_error", "code": "context_length_exceeded"}}"#;
+        assert!(matches!(GptError::from_response(StatusCode::BAD_REQUEST, &headers, context), GptError::ContextLength(_)));
+
+        let auth = r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key"}}"#;
+        assert!(matches!(GptError::from_response(StatusCode::UNAUTHORIZED, &headers, auth), GptError::Auth(_)));
+    }
+
+    #[test]
+    fn test_rate_limit_honours_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        let error = GptError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "{}");
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(7)));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_after_ignores_bogus_values_and_caps_long_waits() {
+        for (name, value, expected) in [
+            ("retry-after", "inf", None),
+            ("retry-after", "NaN", Some(Duration::ZERO)),
+            ("retry-after", "-3", Some(Duration::ZERO)),
+            ("retry-after", "1e300", None),
+            ("retry-after", "86400", Some(MAX_RETRY_AFTER)),
+            ("retry-after-ms", "1500", Some(Duration::from_millis(1500))),
+            ("retry-after-ms", "1e300", None),
+            ("retry-after", "Fri, 31 Dec 9999 23:59:59 GMT", Some(MAX_RETRY_AFTER)),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            assert_eq!(parse_retry_after(&headers), expected, "{}: {}", name, value);
+        }
+    }
+
+    #[test]
+    fn test_classifies_anthropic_overload_and_non_json_bodies() {
+        let headers = HeaderMap::new();
+        let overloaded = r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        let error = GptError::from_response(StatusCode::from_u16(529).unwrap(), &headers, overloaded);
+        assert_eq!(error, GptError::Server { status: 529, message: "Overloaded".to_string() });
+
+        let error = GptError::from_response(StatusCode::BAD_GATEWAY, &headers, "<html>Bad gateway</html>");
+        assert!(error.is_retryable());
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use async_openai::types::Role;
+use base64::{Engine as _, engine::general_purpose};
+use tauri::AppHandle;
+use crate::providers::ChatMessage;
+use crate::stores::get_value_from_store;
+
+/// Replaces the images of older turns so that long threads don't resend every screenshot
+const IMAGE_PLACEHOLDER: &str = "[A screenshot was attached here, it has been left out to keep the conversation short]";
+
+/// An image that belongs to a message, base64 encoded along with its MIME type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageAttachment {
+    pub media_type: &'static str,
+    pub data_base64: String,
+}
+
+impl ImageAttachment {
+    /// The MIME type is taken from the file's signature rather than trusted from its extension.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let media_type = sniff_media_type(bytes).ok_or_else(|| anyhow!("Unsupported image format"))?;
+        Ok(Self {
+            media_type,
+            data_base64: general_purpose::STANDARD.encode(bytes),
+        })
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let buffer = tokio::fs::read(path).await?;
+        Self::from_bytes(&buffer).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data_base64)
+    }
+}
+
+fn sniff_media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// How many of the most recent user turns keep their images (`history_image_turns`). The default
+/// of 1 only sends the screenshot taken for the current question, which is always kept.
+pub fn history_image_turns(app_handle: &AppHandle) -> usize {
+    get_value_from_store(app_handle, "history_image_turns")
+        .and_then(|value| value.as_u64().or_else(|| value.as_str()?.trim().parse().ok()))
+        .map_or(1, |turns| turns.max(1) as usize)
+}
+
+/// Swaps the images of all but the last `keep_turns` user turns with a placeholder.
+pub fn prune_images(messages: &mut [ChatMessage], keep_turns: usize) {
+    let mut user_turns = 0;
+    for msg in messages.iter_mut().rev().filter(|msg| msg.role == Role::User) {
+        user_turns += 1;
+        if user_turns > keep_turns {
+            replace_images_with_placeholder(msg);
+        }
+    }
+}
+
+/// Leaves a placeholder in the text for every image, so the model knows something was there.
+pub fn replace_images_with_placeholder(msg: &mut ChatMessage) {
+    for _ in msg.images.drain(..) {
+        msg.content.push_str("\n\n");
+        msg.content.push_str(IMAGE_PLACEHOLDER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screenshot() -> ImageAttachment {
+        ImageAttachment::from_bytes(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap()
+    }
+
+    #[test]
+    fn test_sniffs_media_type() {
+        assert_eq!(screenshot().media_type, "image/png");
+        assert_eq!(ImageAttachment::from_bytes(&[0xFF, 0xD8, 0xFF, 0xE0]).unwrap().media_type, "image/jpeg");
+        assert!(screenshot().data_url().starts_with("data:image/png;base64,iVBORw0KGgo"));
+        assert!(ImageAttachment::from_bytes(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_prune_keeps_latest_turn_images() {
+        let mut messages = vec![
+            ChatMessage::new(Role::System, "You are Derby"),
+            ChatMessage::new(Role::User, "What's this?").with_images(vec![screenshot(), screenshot()]),
+            ChatMessage::new(Role::Assistant, "A terminal"),
+            ChatMessage::new(Role::User, "And now?").with_images(vec![screenshot()]),
+        ];
+        prune_images(&mut messages, 1);
+
+        assert!(messages[1].images.is_empty());
+        assert_eq!(messages[1].content.matches(IMAGE_PLACEHOLDER).count(), 2);
+        assert_eq!(messages[3].images.len(), 1);
+        assert_eq!(messages[3].content, "And now?");
+    }
+}
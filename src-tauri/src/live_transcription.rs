@@ -0,0 +1,307 @@
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use tauri::{AppHandle, Manager};
+use crate::audio_utils::{TARGET_SAMPLE_RATE, VadSettings, VoiceActivityDetector};
+use crate::stores::get_string_from_store;
+use crate::whisper::{ModelManager, selected_language, selected_model};
+
+/// How much new audio there has to be before the window is transcribed again, in seconds
+const STEP_SECONDS: f64 = 1.0;
+/// Words ending this long before the newest audio are final even if two passes disagree on them,
+/// which keeps the window short
+const FORCE_FINAL_AFTER_SECONDS: f64 = 8.0;
+/// Whisper only hears 30 seconds at a time, so older audio that still isn't final (noise it finds
+/// no words in, or passes that failed) is dropped
+const MAX_WINDOW_SECONDS: f64 = 30.0;
+
+/// A transcribed word, in the shape Deepgram uses. Times are seconds since the recording started.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Word {
+    pub word: String,
+    pub start: f64,
+    pub end: f64,
+    pub confidence: f32,
+    pub speaker: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TranscriptUpdate {
+    /// Words that won't change any more. Each one is only ever reported once.
+    pub final_words: Vec<Word>,
+    /// The current guess for everything after them, replacing the previous guess
+    pub partial_words: Vec<Word>,
+}
+
+/// Decides which words of a sliding window's transcript are final. Whisper often revises the
+/// last few words as more audio arrives, so a word is final once two passes in a row agree on it
+/// and everything before it.
+#[derive(Debug, Default)]
+pub struct TranscriptStabilizer {
+    /// End of the last final word
+    final_until: f64,
+    /// The partial words of the previous pass
+    previous: Vec<Word>,
+}
+
+impl TranscriptStabilizer {
+    pub fn final_until(&self) -> f64 {
+        self.final_until
+    }
+
+    /// Takes the transcript of the current window, `audio_end` being the end of its audio.
+    pub fn update(&mut self, transcript: Vec<Word>, audio_end: f64) -> TranscriptUpdate {
+        let mut words = self.after_final_words(transcript);
+        let agreed = words.iter().zip(&self.previous)
+            .take_while(|(word, previous)| normalize(&word.word) == normalize(&previous.word))
+            .count();
+        let old = words.iter().take_while(|word| word.end <= audio_end - FORCE_FINAL_AFTER_SECONDS).count();
+        let partial_words = words.split_off(agreed.max(old));
+        self.commit(words, partial_words)
+    }
+
+    /// Takes the transcript of the last window, once the recording has stopped.
+    pub fn finish(&mut self, transcript: Vec<Word>) -> TranscriptUpdate {
+        let words = self.after_final_words(transcript);
+        self.commit(words, Vec::new())
+    }
+
+    /// Leaves out words that are already final. Whisper times a word slightly differently in
+    /// every pass, so a word counts as new if most of it is after the last final one.
+    fn after_final_words(&self, transcript: Vec<Word>) -> Vec<Word> {
+        transcript.into_iter()
+            .filter(|word| (word.start + word.end) / 2.0 > self.final_until && !normalize(&word.word).is_empty())
+            .collect()
+    }
+
+    fn commit(&mut self, final_words: Vec<Word>, partial_words: Vec<Word>) -> TranscriptUpdate {
+        if let Some(last) = final_words.last() {
+            self.final_until = self.final_until.max(last.end);
+        }
+        self.previous = partial_words.clone();
+        TranscriptUpdate { final_words, partial_words }
+    }
+}
+
+/// Compares words regardless of case and punctuation, which Whisper changes between passes.
+fn normalize(word: &str) -> String {
+    word.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// Whether recordings are transcribed locally, rather than with Deepgram from the webview. That
+/// takes picking Whisper (`transcription_engine`) and a verified install of the selected model, so
+/// Deepgram stays the default.
+pub fn local_transcription_enabled(app_handle: &AppHandle) -> bool {
+    let engine = get_string_from_store(app_handle, "transcription_engine");
+    let installed = app_handle.state::<ModelManager>().installed(&selected_model(app_handle)).is_some();
+    uses_local_engine(engine.as_deref(), installed)
+}
+
+fn uses_local_engine(engine: Option<&str>, model_installed: bool) -> bool {
+    engine == Some("local") && model_installed
+}
+
+/// The engine recordings use, "local" or "deepgram".
+#[tauri::command]
+pub fn get_transcription_engine(app_handle: AppHandle) -> &'static str {
+    if local_transcription_enabled(&app_handle) { "local" } else { "deepgram" }
+}
+
+/// The transcription that runs while recording.
+#[derive(Default)]
+pub struct LiveTranscription {
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl LiveTranscription {
+    /// Transcribes the audio, mono at `TARGET_SAMPLE_RATE`, until its sender goes away, i.e.
+    /// until the recording stops.
+    pub fn start(&self, app_handle: AppHandle, audio: Receiver<Vec<f32>>) {
+        let mut worker = self.worker.lock().unwrap();
+        if let Some(previous) = worker.take() {
+            let _ = previous.join();
+        }
+        let spawned = thread::Builder::new()
+            .name("live-transcription".to_string())
+            .spawn(move || transcribe_live(app_handle, audio));
+        match spawned {
+            Ok(handle) => *worker = Some(handle),
+            Err(e) => error!("Failed to start live transcription: {}", e),
+        }
+    }
+
+    /// Waits for the last words of a stopped recording.
+    pub fn finish(&self) {
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn transcribe_live(app_handle: AppHandle, audio: Receiver<Vec<f32>>) {
+    let models = app_handle.state::<ModelManager>();
+    let model = selected_model(&app_handle);
+    let language = selected_language(&app_handle);
+    let rate = TARGET_SAMPLE_RATE as f64;
+    let step = (rate * STEP_SECONDS) as usize;
+    let max_window = (rate * MAX_WINDOW_SECONDS) as usize;
+    let vad_settings = VadSettings::from_store(&app_handle);
+    let mut vad = vad_settings.as_ref().map(|settings| VoiceActivityDetector::new(settings, TARGET_SAMPLE_RATE as u32));
+    info!("Transcribing live with Whisper model {}", model);
+
+    let mut stabilizer = TranscriptStabilizer::default();
+    // 16 kHz mono audio from `window_start` on, everything before it is final or was dropped
+    let mut window: Vec<f32> = Vec::new();
+    let mut window_start = 0.0;
+    // Samples that arrived since the last pass
+    let mut new_samples = 0;
+    loop {
+        let (samples, stopped) = receive_queued(&audio);
+        if let Some(vad) = &mut vad {
+            vad.process(&samples);
+        }
+        new_samples += samples.len();
+        window.extend(samples);
+        if !stopped && new_samples < step {
+            continue;
+        }
+        new_samples = 0;
+
+        // Silence isn't worth a pass, Whisper only makes up words for it. The last bit of it is
+        // kept, in case speech starts right after.
+        if let (Some(vad), Some(settings)) = (&vad, &vad_settings) {
+            if !vad.speech_after(window_start) {
+                let keep = (settings.padding.as_secs_f64() * rate) as usize;
+                let silent = window.len().saturating_sub(keep);
+                window.drain(..silent);
+                window_start += silent as f64 / rate;
+                if stopped {
+                    let _ = app_handle.emit_all("transcript", json!({ "words": [], "is_final": false }));
+                    break;
+                }
+                continue;
+            }
+        }
+
+        let dropped = window.len().saturating_sub(max_window);
+        if dropped > 0 {
+            warn!("Dropping {:.1}s of audio that wasn't transcribed in time", dropped as f64 / rate);
+            window.drain(..dropped);
+            window_start += dropped as f64 / rate;
+        }
+
+        let transcript = match models.transcribe_words(&model, &language, &window) {
+            Ok(words) => words.into_iter()
+                .map(|word| Word { start: word.start + window_start, end: word.end + window_start, ..word })
+                .collect(),
+            Err(e) => {
+                error!("Live transcription failed: {:#}", e);
+                if stopped {
+                    break;
+                }
+                continue;
+            }
+        };
+        let audio_end = window_start + window.len() as f64 / rate;
+        let update = if stopped { stabilizer.finish(transcript) } else { stabilizer.update(transcript, audio_end) };
+        if !update.final_words.is_empty() {
+            let _ = app_handle.emit_all("transcript", json!({ "words": update.final_words, "is_final": true }));
+        }
+        let _ = app_handle.emit_all("transcript", json!({ "words": update.partial_words, "is_final": false }));
+        if stopped {
+            break;
+        }
+
+        // Final words aren't transcribed again
+        let final_samples = ((stabilizer.final_until() - window_start) * rate).max(0.0) as usize;
+        let final_samples = final_samples.min(window.len());
+        window.drain(..final_samples);
+        window_start += final_samples as f64 / rate;
+    }
+    info!("Live transcription finished");
+}
+
+/// Waits for audio, then takes everything else that's queued, so a pass that took longer than a
+/// step doesn't leave the next ones behind. Also returns whether the recording has stopped.
+fn receive_queued(audio: &Receiver<Vec<f32>>) -> (Vec<f32>, bool) {
+    let mut samples = match audio.recv() {
+        Ok(samples) => samples,
+        Err(_) => return (Vec::new(), true),
+    };
+    loop {
+        match audio.try_recv() {
+            Ok(more) => samples.extend(more),
+            Err(TryRecvError::Empty) => return (samples, false),
+            Err(TryRecvError::Disconnected) => return (samples, true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str, start: f64, end: f64) -> Word {
+        Word { word: text.to_string(), start, end, confidence: 0.9, speaker: 0 }
+    }
+
+    #[test]
+    fn test_stabilizer_emits_each_final_word_once() {
+        let mut stabilizer = TranscriptStabilizer::default();
+
+        let first = stabilizer.update(vec![word("What's", 0.0, 0.4), word("on", 0.4, 0.6)], 1.0);
+        assert!(first.final_words.is_empty());
+        assert_eq!(first.partial_words.len(), 2);
+
+        // The second pass agrees on the first two words and revises nothing before them
+        let second = stabilizer.update(
+            vec![word("what's", 0.0, 0.45), word("on", 0.45, 0.6), word("my", 0.7, 0.9), word("scream", 1.0, 1.6)],
+            2.0,
+        );
+        assert_eq!(second.final_words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(), vec!["what's", "on"]);
+        assert_eq!(second.partial_words.len(), 2);
+        assert_eq!(stabilizer.final_until(), 0.6);
+
+        // A window that starts at the last final word still mentions part of it
+        let third = stabilizer.update(vec![word("on", 0.5, 0.62), word("my", 0.7, 0.9), word("screen?", 1.0, 1.7)], 3.0);
+        assert_eq!(third.final_words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(), vec!["my"]);
+        assert_eq!(third.partial_words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(), vec!["screen?"]);
+
+        let last = stabilizer.finish(vec![word("screen?", 1.0, 1.7)]);
+        assert_eq!(last.final_words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(), vec!["screen?"]);
+        assert!(last.partial_words.is_empty());
+        assert!(stabilizer.finish(vec![word("screen?", 1.0, 1.7)]).final_words.is_empty());
+
+        // Old words become final even while the passes keep disagreeing
+        let mut stabilizer = TranscriptStabilizer::default();
+        stabilizer.update(vec![word("a", 0.0, 0.5), word("b", 4.0, 4.5)], 5.0);
+        let forced = stabilizer.update(vec![word("the", 0.0, 0.5), word("c", 9.0, 9.5)], 10.0);
+        assert_eq!(forced.final_words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>(), vec!["the"]);
+    }
+
+    #[test]
+    fn test_receive_queued_coalesces_chunks() {
+        let (sender, audio) = std::sync::mpsc::channel();
+        sender.send(vec![0.1; 320]).unwrap();
+        sender.send(vec![0.2; 320]).unwrap();
+        let (samples, stopped) = receive_queued(&audio);
+        assert_eq!(samples.len(), 640);
+        assert!(!stopped);
+
+        sender.send(vec![0.3; 320]).unwrap();
+        drop(sender);
+        assert_eq!(receive_queued(&audio), (vec![0.3; 320], true));
+        assert_eq!(receive_queued(&audio), (Vec::new(), true));
+    }
+
+    #[test]
+    fn test_local_engine_needs_an_installed_model() {
+        assert!(!uses_local_engine(None, true));
+        assert!(!uses_local_engine(Some("deepgram"), true));
+        assert!(!uses_local_engine(Some("local"), false));
+        assert!(uses_local_engine(Some("local"), true));
+    }
+}
@@ -2,12 +2,35 @@
 
 mod stores;
 mod gpt;
+mod gpt_error;
 mod screenshot;
+mod providers;
+mod sse;
+mod conversation;
+mod tools;
+mod requests;
+mod usage;
+mod images;
+mod cassette;
+mod ask;
+mod answer;
+mod personas;
+mod context;
+mod memory;
+mod speech;
+mod credentials;
+mod http_client;
+mod secrets;
+mod audio_utils;
+mod recorder;
+mod whisper;
+mod live_transcription;
+mod shortcuts;
 
 use std::env;
 use dotenv::dotenv;
-use log::{info, LevelFilter};
-use tauri::{ActivationPolicy, AppHandle, CustomMenuItem, GlobalShortcutManager, Manager, SystemTray, SystemTrayMenu, SystemTrayMenuItem, WindowBuilder, WindowUrl};
+use log::{error, info, LevelFilter};
+use tauri::{ActivationPolicy, AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayMenu, SystemTrayMenuItem, WindowBuilder, WindowUrl};
 use tauri::TitleBarStyle::{Transparent};
 use tauri_plugin_autostart::MacosLauncher;
 use tauri_plugin_log::fern::colors::ColoredLevelConfig;
@@ -15,7 +38,23 @@ use tauri_plugin_log::LogTarget;
 use tauri_plugin_positioner::{Position, WindowExt};
 
 use crate::stores::{get_from_store, set_in_store};
-use crate::gpt::check_api_key_validity;
+use crate::gpt::GptClient;
+use crate::credentials::validate_credentials;
+use crate::http_client::apply_network_settings;
+use crate::ask::ask;
+use crate::answer::{copy_answer_block, open_email_draft, save_code_block};
+use crate::memory::{forget_memory, list_memories, MemoryStore, remember_facts, update_memory};
+use crate::speech::{list_voices, skip_sentence, Speaker, stop_speaking};
+use crate::recorder::{Recorder, start_recording, stop_recording};
+use crate::live_transcription::{get_transcription_engine, LiveTranscription};
+use crate::shortcuts::{apply_shortcuts, listen_for_shortcuts, register_shortcuts, Shortcuts};
+use crate::whisper::{delete_whisper_model, handle_model_file, list_whisper_models, ModelManager, transcribe_recording};
+use crate::secrets::{delete_secret, get_config_value, migrate_plaintext_secrets, redact, secrets_status, SecretStore, set_secret, unlock_secrets};
+use crate::personas::{delete_persona, list_personas, os_name, PersonaLibrary, save_persona, set_default_persona};
+use crate::conversation::{ConversationState, get_conversation, new_conversation};
+use crate::tools::{get_tool_settings, set_tool_approval};
+use crate::requests::{cancel_request, RequestRegistry};
+use crate::usage::{get_usage_summary, UsageLedger};
 use crate::screenshot::request_screen_recording_permissions;
 
 const APP_ICON_DEFAULT: &str = "resources/assets/sigma_master_512.png";
@@ -37,9 +76,22 @@ macro_rules! notify {
 fn main() {
     dotenv().ok();
     let tray = tray_setup();
+    let log_colors = ColoredLevelConfig::default();
 
     let mut app = tauri::Builder::default()
         .setup( |app| {
+            let app_data_dir = app.path_resolver().app_data_dir().unwrap_or_default();
+            app.manage(SecretStore::load(app_data_dir.join("secrets.enc")));
+            migrate_plaintext_secrets(&app.handle());
+            app.manage(UsageLedger::load(app_data_dir.join("usage_ledger.json")));
+            app.manage(PersonaLibrary::load(app_data_dir.join("personas.json")));
+            // Personas fill in `{os}` for every question, so it's looked up once up front
+            tauri::async_runtime::spawn_blocking(os_name);
+            app.manage(MemoryStore::load(app_data_dir.join("memories.json")));
+            app.manage(Speaker::new(app.handle()));
+            app.manage(ModelManager::load(app_data_dir.join("whisper_models")));
+            app.manage(GptClient::new(app.handle()));
+
             let app_handle = app.handle();
 
             let is_testing_env = env::var("TESTING_ENV").map(|val| val == "true").unwrap_or(false);
@@ -52,12 +104,18 @@ fn main() {
                 }
             }
 
-            app_handle.global_shortcut_manager().register("F5", move || {
-                toggle_transcription_window(&app_handle);
-            }).unwrap();
+            if let Err(e) = register_shortcuts(&app_handle) {
+                error!("Failed to register shortcuts: {:#}", e);
+            }
+            listen_for_shortcuts(app_handle);
 
             Ok(())
         })
+        .manage(ConversationState::default())
+        .manage(RequestRegistry::default())
+        .manage(Recorder::default())
+        .manage(LiveTranscription::default())
+        .manage(Shortcuts::default())
         .plugin(tauri_plugin_positioner::init())
         .plugin(tauri_plugin_autostart::init(MacosLauncher::LaunchAgent, Some(vec!["--flag1", "--flag2"])))
         .plugin(tauri_plugin_store::Builder::default().build())
@@ -70,12 +128,55 @@ fn main() {
             .level(LevelFilter::Info)
             .level_for("tauri", LevelFilter::Warn)
             .level_for("tao", LevelFilter::Warn)
-            .with_colors(ColoredLevelConfig::default())
+            // Like `with_colors`, but nothing that looks like a secret reaches the log files
+            .format(move |out, message, record| {
+                out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    log_colors.color(record.level()),
+                    redact(&message.to_string())
+                ))
+            })
             .build())
         .invoke_handler(tauri::generate_handler![
             request_screen_recording_permissions,
-            check_api_key_validity,
-            get_env_var
+            validate_credentials,
+            apply_network_settings,
+            ask,
+            copy_answer_block,
+            save_code_block,
+            open_email_draft,
+            list_personas,
+            save_persona,
+            delete_persona,
+            set_default_persona,
+            list_memories,
+            remember_facts,
+            update_memory,
+            forget_memory,
+            stop_speaking,
+            skip_sentence,
+            list_voices,
+            new_conversation,
+            get_conversation,
+            get_tool_settings,
+            set_tool_approval,
+            cancel_request,
+            get_usage_summary,
+            set_secret,
+            delete_secret,
+            secrets_status,
+            unlock_secrets,
+            get_config_value,
+            start_recording,
+            stop_recording,
+            list_whisper_models,
+            handle_model_file,
+            delete_whisper_model,
+            transcribe_recording,
+            get_transcription_engine,
+            apply_shortcuts
         ])
         .system_tray(tray)
         .on_system_tray_event(|app_handle, event| {
@@ -121,6 +222,12 @@ fn toggle_transcription_window(app_handle: &AppHandle) {
         let window = app_handle.get_window("transcription_window").unwrap();
         window.close().unwrap();
     } else {
+        open_transcription_window(app_handle);
+    }
+}
+
+fn open_transcription_window(app_handle: &AppHandle) {
+    if app_handle.get_window("transcription_window").is_none() {
         let new_window = WindowBuilder::new(
             app_handle,
             "transcription_window",
@@ -177,11 +284,3 @@ fn tray_setup() -> SystemTray {
     let tray = SystemTray::new().with_menu(tray_menu).with_menu_on_left_click(false);
     tray
 }
-
-#[tauri::command]
-fn get_env_var(key: &str) -> Result<String, String> {
-    match env::var(key) {
-        Ok(val) => Ok(val),
-        Err(e) => Err(e.to_string()),
-    }
-}
\ No newline at end of file
@@ -0,0 +1,403 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{AppHandle, Manager, State};
+use crate::cassette::Transport;
+use crate::gpt::GptClient;
+use crate::gpt_error::GptError;
+use crate::providers::{OPENAI_BASE_URL, ProviderKind};
+use crate::secrets::get_secret;
+use crate::stores::{get_string_from_store, get_value_from_store};
+use crate::usage::{check_spend_caps, cost_usd, estimate_text_tokens, SpendCheck, UsageLedger};
+
+/// How many memories are added to the system message
+const MEMORY_TOP_K: usize = 5;
+/// Memories less similar to the question than this are left out, even if there's room
+const MIN_SIMILARITY: f32 = 0.2;
+const HASH_EMBEDDING_DIMENSIONS: usize = 256;
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// A fact about the user that Derby remembers between conversations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Memory {
+    pub id: String,
+    pub text: String,
+    pub created_at: String,
+    /// Which embedder produced the embedding. Memories are re-embedded when it changes.
+    #[serde(default)]
+    pub embedder: String,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+/// Turns texts into vectors whose cosine similarity says how related they are.
+pub trait Embedder: Send + Sync + Debug {
+    fn id(&self) -> String;
+
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>>>;
+}
+
+/// A deterministic, offline embedder: every word is hashed into one of a fixed number of buckets.
+/// It only matches shared words, but needs no API and gives the same result every time.
+#[derive(Debug, Default)]
+pub struct HashEmbedder;
+
+impl HashEmbedder {
+    fn embed_text(text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; HASH_EMBEDDING_DIMENSIONS];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| word.len() > 1) {
+            // FNV-1a, which unlike the std hasher is stable across Rust versions
+            let hash = word.to_lowercase().bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % HASH_EMBEDDING_DIMENSIONS as u64) as usize] += sign;
+        }
+        normalize(vector)
+    }
+}
+
+impl Embedder for HashEmbedder {
+    fn id(&self) -> String {
+        format!("hash-{}", HASH_EMBEDDING_DIMENSIONS)
+    }
+
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>>> {
+        Box::pin(async move { Ok(texts.iter().map(|text| Self::embed_text(text)).collect()) })
+    }
+}
+
+/// OpenAI's embeddings endpoint, using the API key from the settings. Its requests count
+/// towards the spend caps like chat requests.
+#[derive(Debug)]
+pub struct OpenAiEmbedder {
+    app_handle: AppHandle,
+    transport: Transport,
+    base_url: String,
+    api_key: String,
+}
+
+impl Embedder for OpenAiEmbedder {
+    fn id(&self) -> String {
+        format!("openai-{}", OPENAI_EMBEDDING_MODEL)
+    }
+
+    fn embed<'a>(&'a self, texts: &'a [String]) -> BoxFuture<'a, Result<Vec<Vec<f32>>>> {
+        Box::pin(async move {
+            let ledger = self.app_handle.state::<UsageLedger>();
+            let estimated_tokens: u32 = texts.iter().map(|text| estimate_text_tokens(text)).sum();
+            let cost = |tokens| cost_usd(ProviderKind::OpenAi, OPENAI_EMBEDDING_MODEL, tokens, 0);
+            match check_spend_caps(&self.app_handle, &ledger, cost(estimated_tokens).unwrap_or_default()) {
+                SpendCheck::Ok => {}
+                SpendCheck::Warn(message) => warn!("{}", message),
+                SpendCheck::Block(message) => return Err(GptError::SpendCapReached(message).into()),
+            }
+
+            let request = self.transport.client()
+                .post(format!("{}/embeddings", self.base_url.trim_end_matches('/')))
+                .header(header::AUTHORIZATION, format!("Bearer {}", self.api_key))
+                .json(&json!({ "model": OPENAI_EMBEDDING_MODEL, "input": texts }));
+            let response = self.transport.send(request).await?;
+            let status = response.status;
+            let headers = response.headers.clone();
+            let body = response.text().await?;
+            if !status.is_success() {
+                return Err(GptError::from_response(status, &headers, &body).into());
+            }
+
+            let value: Value = serde_json::from_str(&body)?;
+            let tokens = value["usage"]["prompt_tokens"].as_u64().map(|tokens| tokens as u32);
+            if let Err(e) = ledger.record(OPENAI_EMBEDDING_MODEL, tokens.unwrap_or(estimated_tokens), 0, cost(tokens.unwrap_or(estimated_tokens)), tokens.is_none()) {
+                error!("Failed to save usage ledger: {}", e);
+            }
+
+            let mut data = value["data"].as_array().cloned().unwrap_or_default();
+            data.sort_by_key(|item| item["index"].as_u64());
+            let embeddings: Vec<Vec<f32>> = data.iter()
+                .map(|item| item["embedding"].as_array().into_iter().flatten().filter_map(|n| n.as_f64()).map(|n| n as f32).collect())
+                .collect();
+            if embeddings.len() != texts.len() {
+                bail!("Expected {} embeddings, got {}", texts.len(), embeddings.len());
+            }
+            Ok(embeddings)
+        })
+    }
+}
+
+/// The embedder chosen on the settings page (`memory_embedder`). OpenAI's is only available when
+/// OpenAI is the provider, everything else uses the local hash embedder.
+pub fn embedder_from_store(app_handle: &AppHandle, transport: Transport) -> Box<dyn Embedder> {
+    let wants_openai = get_string_from_store(app_handle, "memory_embedder").as_deref() == Some("openai");
+    let provider = get_string_from_store(app_handle, "provider").and_then(|value| ProviderKind::from_store_value(&value));
+    let api_key = get_secret(app_handle, "api_token");
+    match (wants_openai, provider.unwrap_or(ProviderKind::OpenAi), api_key) {
+        (true, ProviderKind::OpenAi, Some(api_key)) => Box::new(OpenAiEmbedder {
+            app_handle: app_handle.clone(),
+            transport,
+            base_url: get_string_from_store(app_handle, "provider_base_url")
+                .filter(|url| !url.is_empty())
+                .unwrap_or_else(|| OPENAI_BASE_URL.to_string()),
+            api_key,
+        }),
+        _ => Box::new(HashEmbedder),
+    }
+}
+
+/// Looking for facts costs a request after every answer, so it's off until the user turns it on.
+pub fn memory_enabled(app_handle: &AppHandle) -> bool {
+    get_value_from_store(app_handle, "memory_enabled")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false)
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// The model is asked for facts as a JSON array of strings, possibly wrapped in prose or a code block.
+pub fn parse_fact_list(text: &str) -> Vec<String> {
+    let (Some(start), Some(end)) = (text.find('['), text.rfind(']')) else {
+        return Vec::new();
+    };
+    if end < start {
+        return Vec::new();
+    }
+    serde_json::from_str::<Vec<String>>(&text[start..=end])
+        .unwrap_or_default()
+        .into_iter()
+        .map(|fact| fact.trim().to_string())
+        .filter(|fact| !fact.is_empty())
+        .collect()
+}
+
+/// The memories, kept in `memories.json` in the app data dir.
+#[derive(Debug)]
+pub struct MemoryStore {
+    path: PathBuf,
+    memories: Mutex<Vec<Memory>>,
+}
+
+impl MemoryStore {
+    pub fn load(path: PathBuf) -> Self {
+        let memories = std::fs::read_to_string(&path).ok()
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| error!("Failed to parse memories: {}", e)).ok())
+            .unwrap_or_default();
+        Self { path, memories: Mutex::new(memories) }
+    }
+
+    pub fn list(&self) -> Vec<Memory> {
+        self.memories.lock().unwrap().clone()
+    }
+
+    /// Drops the facts that are already remembered, so the user isn't asked to confirm them again.
+    pub fn new_facts(&self, facts: Vec<String>) -> Vec<String> {
+        let memories = self.memories.lock().unwrap();
+        facts.into_iter()
+            .filter(|fact| !memories.iter().any(|memory| memory.text.eq_ignore_ascii_case(fact)))
+            .collect()
+    }
+
+    pub async fn remember(&self, embedder: &dyn Embedder, facts: Vec<String>) -> Result<Vec<Memory>> {
+        let facts = self.new_facts(facts);
+        if facts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let embeddings = embedder.embed(&facts).await?;
+        let now = chrono::Utc::now();
+        let added: Vec<Memory> = facts.into_iter().zip(embeddings).enumerate().map(|(i, (text, embedding))| Memory {
+            id: format!("mem-{}-{}", now.timestamp_millis(), i),
+            text,
+            created_at: now.to_rfc3339(),
+            embedder: embedder.id(),
+            embedding,
+        }).collect();
+
+        let mut memories = self.memories.lock().unwrap();
+        memories.extend(added.iter().cloned());
+        self.save(&memories)?;
+        Ok(added)
+    }
+
+    pub async fn update(&self, embedder: &dyn Embedder, id: &str, text: String) -> Result<()> {
+        let embedding = embedder.embed(std::slice::from_ref(&text)).await?.pop().unwrap_or_default();
+        let mut memories = self.memories.lock().unwrap();
+        let memory = memories.iter_mut().find(|memory| memory.id == id).ok_or_else(|| anyhow!("Memory {} not found", id))?;
+        memory.text = text;
+        memory.embedding = embedding;
+        memory.embedder = embedder.id();
+        self.save(&memories)
+    }
+
+    pub fn forget(&self, id: &str) -> Result<()> {
+        let mut memories = self.memories.lock().unwrap();
+        let count = memories.len();
+        memories.retain(|memory| memory.id != id);
+        if memories.len() == count {
+            bail!("Memory {} not found", id);
+        }
+        self.save(&memories)
+    }
+
+    /// The `k` memories most related to the query, most related first.
+    pub async fn relevant(&self, embedder: &dyn Embedder, query: &str, k: usize) -> Result<Vec<String>> {
+        self.reembed_stale(embedder).await?;
+        let query = embedder.embed(&[query.to_string()]).await?.pop().unwrap_or_default();
+
+        let memories = self.memories.lock().unwrap();
+        let mut scored: Vec<(f32, &Memory)> = memories.iter()
+            .map(|memory| (cosine_similarity(&query, &memory.embedding), memory))
+            .filter(|(score, _)| *score >= MIN_SIMILARITY)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored.into_iter().take(k).map(|(_, memory)| memory.text.clone()).collect())
+    }
+
+    /// Embeddings from different embedders can't be compared, so after the embedder changes the
+    /// memories are embedded again.
+    async fn reembed_stale(&self, embedder: &dyn Embedder) -> Result<()> {
+        let id = embedder.id();
+        let stale: Vec<(String, String)> = self.memories.lock().unwrap().iter()
+            .filter(|memory| memory.embedder != id)
+            .map(|memory| (memory.id.clone(), memory.text.clone()))
+            .collect();
+        if stale.is_empty() {
+            return Ok(());
+        }
+        info!("Embedding {} memories with {}", stale.len(), id);
+        let texts: Vec<String> = stale.iter().map(|(_, text)| text.clone()).collect();
+        let embeddings = embedder.embed(&texts).await?;
+
+        let mut memories = self.memories.lock().unwrap();
+        for ((memory_id, _), embedding) in stale.into_iter().zip(embeddings) {
+            if let Some(memory) = memories.iter_mut().find(|memory| memory.id == memory_id) {
+                memory.embedding = embedding;
+                memory.embedder = id.clone();
+            }
+        }
+        self.save(&memories)
+    }
+
+    fn save(&self, memories: &[Memory]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(memories)?)?;
+        Ok(())
+    }
+}
+
+/// The memories to add to the system message for a question. Failures only cost the memories,
+/// never the answer.
+pub async fn relevant_memories(app_handle: &AppHandle, transport: Transport, question: &str) -> Vec<String> {
+    if !memory_enabled(app_handle) {
+        return Vec::new();
+    }
+    let embedder = embedder_from_store(app_handle, transport);
+    let store = app_handle.state::<MemoryStore>();
+    store.relevant(embedder.as_ref(), question, MEMORY_TOP_K).await.unwrap_or_else(|e| {
+        warn!("Failed to look up memories: {}", e);
+        Vec::new()
+    })
+}
+
+/// Looks for new facts about the user in a finished exchange and offers them to the user with a
+/// `memory_candidates` event. They're only stored once confirmed through `remember_facts`.
+pub fn offer_memories_from_exchange(app_handle: AppHandle, question: String, answer: String) {
+    if !memory_enabled(&app_handle) {
+        return;
+    }
+    tauri::async_runtime::spawn(async move {
+        let gpt_client = app_handle.state::<GptClient>();
+        let facts = match gpt_client.extract_facts(&question, &answer, &app_handle).await {
+            Ok(facts) => app_handle.state::<MemoryStore>().new_facts(facts),
+            Err(e) => {
+                warn!("Failed to extract memories: {}", e);
+                return;
+            }
+        };
+        if facts.is_empty() {
+            return;
+        }
+        info!("Offering {} new memories", facts.len());
+        if let Err(e) = app_handle.emit_all("memory_candidates", json!({ "facts": facts })) {
+            error!("Failed to emit memory_candidates: {}", e);
+        }
+    });
+}
+
+#[tauri::command]
+pub fn list_memories(store: State<'_, MemoryStore>) -> Vec<Memory> {
+    store.list().into_iter().map(|memory| Memory { embedding: Vec::new(), ..memory }).collect()
+}
+
+#[tauri::command]
+pub async fn remember_facts(app_handle: AppHandle, store: State<'_, MemoryStore>, gpt_client: State<'_, GptClient>, facts: Vec<String>) -> Result<usize, String> {
+    let embedder = embedder_from_store(&app_handle, gpt_client.transport().clone());
+    let added = store.remember(embedder.as_ref(), facts).await.map_err(|e| e.to_string())?;
+    info!("Remembered {} facts", added.len());
+    Ok(added.len())
+}
+
+#[tauri::command]
+pub async fn update_memory(app_handle: AppHandle, store: State<'_, MemoryStore>, gpt_client: State<'_, GptClient>, id: String, text: String) -> Result<(), String> {
+    let embedder = embedder_from_store(&app_handle, gpt_client.transport().clone());
+    store.update(embedder.as_ref(), &id, text).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn forget_memory(store: State<'_, MemoryStore>, id: String) -> Result<(), String> {
+    store.forget(&id).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remember_and_retrieve_with_hash_embedder() {
+        let path = std::env::temp_dir().join(format!("derby_memories_test_{}.json", std::process::id()));
+        let store = MemoryStore::load(path.clone());
+        let embedder = HashEmbedder;
+        let facts = vec![
+            "Uses Rust and Neovim for work".to_string(),
+            "Prefers brief answers".to_string(),
+            "Has a dog called Biscuit".to_string(),
+        ];
+        assert_eq!(store.remember(&embedder, facts.clone()).await.unwrap().len(), 3);
+        // Already known facts aren't added twice
+        assert!(store.remember(&embedder, vec!["prefers brief answers".to_string()]).await.unwrap().is_empty());
+
+        let relevant = store.relevant(&embedder, "How do I set up rust-analyzer in Neovim?", 2).await.unwrap();
+        assert_eq!(relevant, vec!["Uses Rust and Neovim for work".to_string()]);
+
+        let id = store.list()[2].id.clone();
+        store.forget(&id).unwrap();
+        assert_eq!(MemoryStore::load(path.clone()).list().len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_parse_fact_list() {
+        assert_eq!(parse_fact_list("```json\n[\"Uses Rust\", \" \"]\n```"), vec!["Uses Rust".to_string()]);
+        assert!(parse_fact_list("There is nothing new to remember.").is_empty());
+    }
+}
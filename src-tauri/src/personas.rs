@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, State};
+use crate::stores::get_string_from_store;
+
+const DEFAULT_PERSONA_ID: &str = "derby";
+/// How long the template waits for the frontmost app before doing without it
+const FRONTMOST_APP_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DERBY_TEMPLATE: &str = "This is an AI macos app where the user questions/tasks the AI via speech-to-text.\
+    The app also takes a screenshot of the user's screen and sends it to the AI so that the AI can use it to answer the user's question.\
+    Don't refer to the 'screenshot' in your response, instead call it their 'screen' or 'desktop'.\
+    Sometimes the user might just say something that is not related to the screenshot. In this case, just ignore the screenshot and respond normally.\
+     Since the user uses speech-to-text to communicate, so some of their messages may be incorrect - make assumptions (on incorrect words etc.) based on this.\
+     In The user will be unable to respond to you after you send a message, so do not ask any questions or ask for clarification.\
+      Ensure that your output is just the output they requested - do not ask any follow up questions or include any extra text.\
+\n\nYou are helping {first_name}. Today is {date} and they are using {os}.";
+
+const CODE_REVIEWER_TEMPLATE: &str = "You are a senior engineer reviewing the code on {first_name}'s screen. \
+Point out bugs, security problems and unclear code, most important first, and show corrected code in fenced code blocks \
+with the language. Be direct and skip praise. The code is open in {frontmost_app} on {os}.";
+
+const EMAIL_WRITER_TEMPLATE: &str = "You write emails for {first_name}, using what's on their screen as context. \
+Start every draft with a `Subject:` line, followed by the body. Match the tone of the thread you're replying to, \
+keep it short and sign it with {first_name}'s name. Today is {date}.";
+
+const TECH_SUPPORT_TEMPLATE: &str = "You are a patient tech support agent helping {first_name} with a problem on their computer, \
+which runs {os}. They are looking at {frontmost_app}. Explain the fix as numbered steps, one action per step, \
+naming the exact menus and buttons to click.";
+
+/// A named system prompt. `{first_name}`, `{date}`, `{os}` and `{frontmost_app}` in the template are
+/// filled in for every question. Looking up the frontmost app needs the Automation permission and
+/// tells the provider what the user is doing, so only personas that need it use it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Persona {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub template: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonaList {
+    pub default_persona_id: String,
+    pub personas: Vec<Persona>,
+}
+
+impl Default for PersonaList {
+    fn default() -> Self {
+        let persona = |id: &str, name: &str, template: &str| Persona {
+            id: id.to_string(),
+            name: name.to_string(),
+            template: template.to_string(),
+        };
+        Self {
+            default_persona_id: DEFAULT_PERSONA_ID.to_string(),
+            personas: vec![
+                persona(DEFAULT_PERSONA_ID, "Derby", DERBY_TEMPLATE),
+                persona("code_reviewer", "Code reviewer", CODE_REVIEWER_TEMPLATE),
+                persona("email_writer", "Email writer", EMAIL_WRITER_TEMPLATE),
+                persona("tech_support", "Tech support", TECH_SUPPORT_TEMPLATE),
+            ],
+        }
+    }
+}
+
+/// The persona library, kept in `personas.json` in the app data dir. The built-in personas are
+/// written there on first use and can be edited like any other.
+#[derive(Debug)]
+pub struct PersonaLibrary {
+    path: PathBuf,
+    list: Mutex<PersonaList>,
+}
+
+impl PersonaLibrary {
+    pub fn load(path: PathBuf) -> Self {
+        let list = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                error!("Failed to parse persona library, using the built-in personas: {}", e);
+                PersonaList::default()
+            }),
+            Err(_) => {
+                info!("No persona library yet, using the built-in personas");
+                PersonaList::default()
+            }
+        };
+        Self { path, list: Mutex::new(list) }
+    }
+
+    pub fn list(&self) -> PersonaList {
+        self.list.lock().unwrap().clone()
+    }
+
+    /// The persona with the given id, falling back to the default one.
+    pub fn get_or_default(&self, id: Option<&str>) -> Option<Persona> {
+        let list = self.list.lock().unwrap();
+        let find = |id: &str| list.personas.iter().find(|persona| persona.id == id).cloned();
+        match id.and_then(find) {
+            Some(persona) => Some(persona),
+            None => {
+                if let Some(id) = id {
+                    warn!("Persona {} not found, using the default", id);
+                }
+                find(&list.default_persona_id).or_else(|| list.personas.first().cloned())
+            }
+        }
+    }
+
+    /// Creates the persona, or updates it if one with the same id exists. New personas get an id
+    /// derived from their name.
+    pub fn save_persona(&self, mut persona: Persona) -> Result<Persona> {
+        if persona.name.trim().is_empty() {
+            bail!("A persona needs a name");
+        }
+        let mut list = self.list.lock().unwrap();
+        match list.personas.iter_mut().find(|existing| !persona.id.is_empty() && existing.id == persona.id) {
+            Some(existing) => *existing = persona.clone(),
+            None => {
+                persona.id = unique_id(&persona.name, &list.personas);
+                list.personas.push(persona.clone());
+            }
+        }
+        self.save(&list)?;
+        Ok(persona)
+    }
+
+    pub fn delete_persona(&self, id: &str) -> Result<()> {
+        let mut list = self.list.lock().unwrap();
+        if list.personas.len() == 1 {
+            bail!("The last persona can't be deleted");
+        }
+        let index = list.personas.iter().position(|persona| persona.id == id)
+            .ok_or_else(|| anyhow!("Persona {} not found", id))?;
+        list.personas.remove(index);
+        if list.default_persona_id == id {
+            list.default_persona_id = list.personas[0].id.clone();
+        }
+        self.save(&list)
+    }
+
+    pub fn set_default(&self, id: &str) -> Result<()> {
+        let mut list = self.list.lock().unwrap();
+        if !list.personas.iter().any(|persona| persona.id == id) {
+            bail!("Persona {} not found", id);
+        }
+        list.default_persona_id = id.to_string();
+        self.save(&list)
+    }
+
+    fn save(&self, list: &PersonaList) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(list)?)?;
+        Ok(())
+    }
+}
+
+/// `Email writer` becomes `email_writer`, with a number added if that's taken.
+fn unique_id(name: &str, personas: &[Persona]) -> String {
+    let slug: String = name.trim().to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let slug = slug.trim_matches('_').to_string();
+    let slug = if slug.is_empty() { "persona".to_string() } else { slug };
+
+    let taken = |id: &str| personas.iter().any(|persona| persona.id == id);
+    if !taken(&slug) {
+        return slug;
+    }
+    (2..).map(|n| format!("{}_{}", slug, n)).find(|id| !taken(id)).unwrap()
+}
+
+/// Fills in the template variables. Values are only looked up for the variables the template uses,
+/// since finding the frontmost app runs AppleScript.
+pub async fn render_template(app_handle: &AppHandle, template: &str) -> String {
+    let mut rendered = template.to_string();
+    if rendered.contains("{first_name}") {
+        let first_name = get_string_from_store(app_handle, "userFirstName")
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "the user".to_string());
+        rendered = rendered.replace("{first_name}", &first_name);
+    }
+    if rendered.contains("{date}") {
+        rendered = rendered.replace("{date}", &Local::now().format("%A, %B %-d, %Y").to_string());
+    }
+    if rendered.contains("{os}") {
+        rendered = rendered.replace("{os}", os_name());
+    }
+    if rendered.contains("{frontmost_app}") {
+        let app = frontmost_app().await.unwrap_or_else(|| "an unknown app".to_string());
+        rendered = rendered.replace("{frontmost_app}", &app);
+    }
+    rendered
+}
+
+/// The OS and its version. `sw_vers` only runs once, which `main` does at startup.
+pub fn os_name() -> &'static str {
+    static OS_NAME: OnceLock<String> = OnceLock::new();
+    OS_NAME.get_or_init(|| {
+        let version = Command::new("sw_vers").arg("-productVersion").output().ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        match version {
+            Some(version) if !version.is_empty() => format!("macOS {}", version),
+            _ => std::env::consts::OS.to_string(),
+        }
+    })
+}
+
+/// Runs the AppleScript off the async runtime, giving up when it hangs, e.g. on the automation
+/// permission prompt.
+async fn frontmost_app() -> Option<String> {
+    match tokio::time::timeout(FRONTMOST_APP_TIMEOUT, tokio::task::spawn_blocking(read_frontmost_app)).await {
+        Ok(Ok(app)) => app,
+        Ok(Err(e)) => {
+            error!("Failed to get the frontmost app: {}", e);
+            None
+        }
+        Err(_) => {
+            warn!("The frontmost app didn't arrive within {:?}", FRONTMOST_APP_TIMEOUT);
+            None
+        }
+    }
+}
+
+fn read_frontmost_app() -> Option<String> {
+    let output = Command::new("osascript")
+        .arg("-e")
+        .arg("tell application \"System Events\" to get name of first application process whose frontmost is true")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        warn!("Failed to get the frontmost app: {}", String::from_utf8_lossy(&output.stderr).trim());
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|app| !app.is_empty())
+}
+
+#[tauri::command]
+pub fn list_personas(library: State<'_, PersonaLibrary>) -> PersonaList {
+    library.list()
+}
+
+#[tauri::command]
+pub fn save_persona(library: State<'_, PersonaLibrary>, persona: Persona) -> Result<Persona, String> {
+    library.save_persona(persona).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn delete_persona(library: State<'_, PersonaLibrary>, id: String) -> Result<(), String> {
+    library.delete_persona(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn set_default_persona(library: State<'_, PersonaLibrary>, id: String) -> Result<(), String> {
+    library.set_default(&id).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persona_crud() {
+        let path = std::env::temp_dir().join(format!("derby_personas_test_{}.json", std::process::id()));
+        let library = PersonaLibrary::load(path.clone());
+        assert_eq!(library.get_or_default(None).unwrap().id, DEFAULT_PERSONA_ID);
+        assert!(!library.get_or_default(None).unwrap().template.contains("{frontmost_app}"));
+
+        let persona = Persona { id: String::new(), name: "Code Reviewer".to_string(), template: "Be strict.".to_string() };
+        let created = library.save_persona(persona).unwrap();
+        assert_eq!(created.id, "code_reviewer_2");
+
+        library.set_default(&created.id).unwrap();
+        library.save_persona(Persona { template: "Be kind.".to_string(), ..created.clone() }).unwrap();
+        assert_eq!(library.get_or_default(Some("missing")).unwrap().template, "Be kind.");
+
+        // Reloading reads back what was saved
+        let reloaded = PersonaLibrary::load(path.clone());
+        reloaded.delete_persona(&created.id).unwrap();
+        assert_eq!(reloaded.list().default_persona_id, DEFAULT_PERSONA_ID);
+        assert_eq!(reloaded.list().personas.len(), 4);
+        let _ = std::fs::remove_file(path);
+    }
+}
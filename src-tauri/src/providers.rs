@@ -0,0 +1,804 @@
+use std::fmt::Debug;
+use anyhow::{anyhow, Result};
+use async_openai::types::Role;
+use reqwest::{Client, header, RequestBuilder};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tauri::AppHandle;
+use crate::gpt_error::GptError;
+use crate::images::ImageAttachment;
+use crate::secrets::get_secret;
+use crate::sse::SseEvent;
+use crate::stores::{get_number_from_store, get_string_from_store, get_value_from_store};
+use crate::tools::{ToolRound, ToolSpec};
+
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+pub const OLLAMA_BASE_URL: &str = "http://localhost:11434/v1";
+pub const AZURE_DEFAULT_API_VERSION: &str = "2023-12-01-preview";
+
+pub const OPENAI_DEFAULT_MODEL: &str = "gpt-4-vision-preview";
+pub const ANTHROPIC_DEFAULT_MODEL: &str = "claude-3-opus-20240229";
+pub const OLLAMA_DEFAULT_MODEL: &str = "llava";
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+const MAX_STOP_SEQUENCES_OPENAI: usize = 4;
+const MAX_STOP_SEQUENCES_ANTHROPIC: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    OpenAi,
+    Anthropic,
+    AzureOpenAi,
+    Ollama,
+}
+
+impl ProviderKind {
+    pub fn from_store_value(value: &str) -> Option<Self> {
+        match value {
+            "openai" => Some(Self::OpenAi),
+            "anthropic" => Some(Self::Anthropic),
+            "azure_openai" => Some(Self::AzureOpenAi),
+            "ollama" => Some(Self::Ollama),
+            _ => None,
+        }
+    }
+
+    pub fn as_store_value(&self) -> &'static str {
+        match self {
+            Self::OpenAi => "openai",
+            Self::Anthropic => "anthropic",
+            Self::AzureOpenAi => "azure_openai",
+            Self::Ollama => "ollama",
+        }
+    }
+}
+
+/// How closely the model looks at the screenshot, which is also what it gets billed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageDetail {
+    Low,
+    High,
+    Auto,
+}
+
+impl ImageDetail {
+    pub fn from_store(app_handle: &AppHandle) -> Self {
+        match get_string_from_store(app_handle, "image_detail").as_deref() {
+            Some("low") => Self::Low,
+            Some("high") => Self::High,
+            _ => Self::Auto,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::High => "high",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+/// Sampling parameters for a request, configured on the settings page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub max_tokens: u32,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,
+}
+
+impl GenerationParams {
+    /// Reads `max_tokens`, `temperature`, `top_p` and `stop_sequences` from the store and validates
+    /// them for the given provider and model.
+    pub fn from_store(app_handle: &AppHandle, provider: &dyn ChatProvider) -> Result<Self, GptError> {
+        let number = |key: &str| get_number_from_store(app_handle, key);
+        let stop = match get_value_from_store(app_handle, "stop_sequences") {
+            Some(Value::Array(values)) => values.iter().filter_map(|v| v.as_str().map(str::to_owned)).collect(),
+            Some(Value::String(text)) => text.lines().map(str::to_owned).collect(),
+            _ => Vec::new(),
+        };
+
+        let params = Self {
+            max_tokens: number("max_tokens").filter(|n| *n > 0.0).map_or(DEFAULT_MAX_TOKENS, |n| n as u32),
+            temperature: number("temperature").map(|n| n as f32),
+            top_p: number("top_p").map(|n| n as f32),
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+        };
+        params.validate(provider.kind(), provider.model())?;
+        Ok(params)
+    }
+
+    pub fn validate(&self, provider: ProviderKind, model: &str) -> Result<(), GptError> {
+        let max_output_tokens = max_output_tokens(provider, model);
+        if self.max_tokens > max_output_tokens {
+            return Err(GptError::InvalidSettings(format!(
+                "Max tokens is {}, but {} can generate at most {} tokens per answer.", self.max_tokens, model, max_output_tokens
+            )));
+        }
+
+        let max_temperature = if provider == ProviderKind::Anthropic { 1.0 } else { 2.0 };
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=max_temperature).contains(&temperature) {
+                return Err(GptError::InvalidSettings(format!("Temperature must be between 0 and {}.", max_temperature)));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(GptError::InvalidSettings("Top-p must be between 0 and 1.".to_string()));
+            }
+        }
+
+        let max_stop_sequences = match provider {
+            ProviderKind::Anthropic => MAX_STOP_SEQUENCES_ANTHROPIC,
+            _ => MAX_STOP_SEQUENCES_OPENAI,
+        };
+        if self.stop.len() > max_stop_sequences {
+            return Err(GptError::InvalidSettings(format!("At most {} stop sequences are supported.", max_stop_sequences)));
+        }
+        Ok(())
+    }
+}
+
+/// The most tokens a model will generate for one answer. Local models are only limited by their context.
+pub fn max_output_tokens(provider: ProviderKind, model: &str) -> u32 {
+    match provider {
+        ProviderKind::Ollama => u32::MAX,
+        ProviderKind::Anthropic if model.starts_with("claude-3-5") => 8192,
+        ProviderKind::Anthropic => 4096,
+        _ if model.starts_with("gpt-4o") => 16384,
+        _ => 4096,
+    }
+}
+
+/// A message of the conversation together with the images attached to it (usually the screenshot
+/// taken when the user asked the question).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    pub images: Vec<ImageAttachment>,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            images: Vec::new(),
+        }
+    }
+
+    pub fn with_images(mut self, images: Vec<ImageAttachment>) -> Self {
+        self.images = images;
+        self
+    }
+}
+
+/// How many tokens the model can take in one request, prompt and answer together. Azure deployments
+/// are usually named after their model, anything unknown gets a conservative 8k.
+pub fn context_length(provider: ProviderKind, model: &str) -> u32 {
+    const OPENAI_128K: &[&str] = &["gpt-4o", "gpt-4-turbo", "gpt-4-vision-preview", "gpt-4-1106", "gpt-4-0125"];
+    match provider {
+        ProviderKind::Anthropic => 200_000,
+        // Ollama's context window depends on the model file, this is what llava ships with
+        ProviderKind::Ollama => 4096,
+        _ if OPENAI_128K.iter().any(|prefix| model.starts_with(prefix)) => 128_000,
+        _ if model.starts_with("gpt-4-32k") => 32_768,
+        _ if model.starts_with("gpt-3.5-turbo") => 16_385,
+        _ => 8192,
+    }
+}
+
+/// Whether the model can look at screenshots. Azure deployments are usually named after their model.
+pub fn supports_vision(provider: ProviderKind, model: &str) -> bool {
+    const OPENAI_VISION: &[&str] = &["gpt-4o", "gpt-4-turbo", "gpt-4-vision", "gpt-4-1106-vision"];
+    const OLLAMA_VISION: &[&str] = &["llava", "bakllava", "moondream", "minicpm-v", "llama3.2-vision"];
+    let model = model.to_lowercase();
+    match provider {
+        // Every Claude 3 model and later takes images
+        ProviderKind::Anthropic => !model.starts_with("claude-2") && !model.starts_with("claude-instant"),
+        ProviderKind::Ollama => OLLAMA_VISION.iter().any(|name| model.starts_with(name)),
+        _ => OPENAI_VISION.iter().any(|prefix| model.starts_with(prefix)),
+    }
+}
+
+/// Whether the model accepts `tools`, the vision previews and most local models reject requests
+/// that have them. Azure deployments are usually named after their model.
+pub fn supports_tools(provider: ProviderKind, model: &str) -> bool {
+    const OPENAI_TOOLS: &[&str] = &["gpt-4o", "gpt-4-turbo", "gpt-4-1106-preview", "gpt-4-0125-preview", "gpt-4-0613", "gpt-3.5-turbo"];
+    const OLLAMA_TOOLS: &[&str] = &["llama3.1", "llama3.2", "llama3.3", "mistral-nemo", "mistral-large", "qwen2", "command-r", "firefunction"];
+    let model = model.to_lowercase();
+    match provider {
+        ProviderKind::Anthropic => !model.starts_with("claude-2") && !model.starts_with("claude-instant"),
+        // Vision variants of tool-capable families, like llama3.2-vision, don't take tools
+        ProviderKind::Ollama => !model.contains("vision") && OLLAMA_TOOLS.iter().any(|name| model.starts_with(name)),
+        _ => model == "gpt-4" || OPENAI_TOOLS.iter().any(|prefix| model.starts_with(prefix)),
+    }
+}
+
+/// Adds the `OpenAI-Organization` and `OpenAI-Project` headers, for keys that belong to more than one.
+pub fn with_openai_account_headers(mut builder: RequestBuilder, organization: Option<&str>, project: Option<&str>) -> RequestBuilder {
+    if let Some(organization) = organization {
+        builder = builder.header("OpenAI-Organization", organization);
+    }
+    if let Some(project) = project {
+        builder = builder.header("OpenAI-Project", project);
+    }
+    builder
+}
+
+/// The provider-agnostic input for a single chat completion: the conversation so far, with the
+/// images attached to the messages they belong to.
+#[derive(Debug)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    pub image_detail: ImageDetail,
+    pub params: GenerationParams,
+    pub tools: Vec<ToolSpec>,
+    /// Tool calls made while answering the latest message, sent after it in order
+    pub tool_rounds: Vec<ToolRound>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+}
+
+impl TokenUsage {
+    /// Providers may report usage in several events (Anthropic sends the prompt tokens first and the
+    /// completion tokens at the end), so later values only overwrite the fields they actually contain.
+    pub fn merge(&mut self, other: TokenUsage) {
+        self.prompt_tokens = other.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = other.completion_tokens.or(self.completion_tokens);
+    }
+}
+
+/// What a provider's streamed SSE event means, independent of its wire format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    Content(String),
+    /// A fragment of a tool call. The id and name arrive once, the arguments JSON in pieces.
+    ToolCallDelta {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    Finish(String),
+    Usage(TokenUsage),
+    /// An error reported inside an otherwise successful (HTTP 200) stream
+    Error(GptError),
+    Done,
+}
+
+/// A chat backend. Each provider maps a `ChatRequest` to its own wire format and knows how to
+/// interpret the events it streams back.
+pub trait ChatProvider: Send + Sync + Debug {
+    fn kind(&self) -> ProviderKind;
+
+    /// The model name used for pricing, the deployment name for Azure
+    fn model(&self) -> &str;
+
+    fn build_request(&self, client: &Client, request: &ChatRequest) -> Result<RequestBuilder>;
+
+    fn parse_event(&self, event: &SseEvent) -> Vec<StreamEvent>;
+}
+
+/// Builds the provider selected in the settings store (`provider`), defaulting to OpenAI.
+pub fn provider_from_store(app_handle: &AppHandle) -> Result<Box<dyn ChatProvider>> {
+    let kind = match get_string_from_store(app_handle, "provider") {
+        Some(value) => ProviderKind::from_store_value(&value)
+            .ok_or_else(|| anyhow!("Unknown provider in settings: {}", value))?,
+        None => ProviderKind::OpenAi,
+    };
+
+    let api_key = get_secret(app_handle, "api_token");
+    let base_url = get_string_from_store(app_handle, "provider_base_url").filter(|url| !url.is_empty());
+    let model = get_string_from_store(app_handle, "model").filter(|model| !model.is_empty());
+
+    let provider: Box<dyn ChatProvider> = match kind {
+        ProviderKind::OpenAi => Box::new(OpenAiProvider {
+            organization: get_string_from_store(app_handle, "openai_organization").filter(|id| !id.is_empty()),
+            project: get_string_from_store(app_handle, "openai_project").filter(|id| !id.is_empty()),
+            ..OpenAiProvider::new(
+                base_url.unwrap_or_else(|| OPENAI_BASE_URL.to_string()),
+                api_key.ok_or_else(|| anyhow!("OpenAI API key not found"))?,
+                model.unwrap_or_else(|| OPENAI_DEFAULT_MODEL.to_string()),
+            )
+        }),
+        ProviderKind::Anthropic => Box::new(AnthropicProvider {
+            base_url: base_url.unwrap_or_else(|| ANTHROPIC_BASE_URL.to_string()),
+            api_key: api_key.ok_or_else(|| anyhow!("Anthropic API key not found"))?,
+            model: model.unwrap_or_else(|| ANTHROPIC_DEFAULT_MODEL.to_string()),
+        }),
+        ProviderKind::AzureOpenAi => Box::new(AzureOpenAiProvider {
+            endpoint: base_url.ok_or_else(|| anyhow!("Azure OpenAI endpoint not found"))?,
+            deployment: get_string_from_store(app_handle, "azure_deployment")
+                .ok_or_else(|| anyhow!("Azure OpenAI deployment not found"))?,
+            api_version: get_string_from_store(app_handle, "azure_api_version")
+                .unwrap_or_else(|| AZURE_DEFAULT_API_VERSION.to_string()),
+            api_key: api_key.ok_or_else(|| anyhow!("Azure OpenAI API key not found"))?,
+        }),
+        ProviderKind::Ollama => Box::new(OllamaProvider {
+            base_url: base_url.unwrap_or_else(|| OLLAMA_BASE_URL.to_string()),
+            api_key,
+            model: model.unwrap_or_else(|| OLLAMA_DEFAULT_MODEL.to_string()),
+        }),
+    };
+
+    Ok(provider)
+}
+
+#[derive(Debug)]
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: String,
+    model: String,
+    organization: Option<String>,
+    project: Option<String>,
+}
+
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: String, model: String) -> Self {
+        Self { base_url, api_key, model, organization: None, project: None }
+    }
+}
+
+impl ChatProvider for OpenAiProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::OpenAi
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn build_request(&self, client: &Client, request: &ChatRequest) -> Result<RequestBuilder> {
+        let mut payload = openai_payload(Some(&self.model), request);
+        payload["stream_options"] = json!({ "include_usage": true });
+        let builder = client
+            .post(format!("{}/chat/completions", self.base_url.trim_end_matches('/')))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.api_key))
+            .json(&payload);
+        Ok(with_openai_account_headers(builder, self.organization.as_deref(), self.project.as_deref()))
+    }
+
+    fn parse_event(&self, event: &SseEvent) -> Vec<StreamEvent> {
+        openai_parse_event(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct AzureOpenAiProvider {
+    endpoint: String,
+    deployment: String,
+    api_version: String,
+    api_key: String,
+}
+
+impl ChatProvider for AzureOpenAiProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::AzureOpenAi
+    }
+
+    fn model(&self) -> &str {
+        &self.deployment
+    }
+
+    fn build_request(&self, client: &Client, request: &ChatRequest) -> Result<RequestBuilder> {
+        // Azure picks the model from the deployment in the URL, so it's left out of the body
+        let payload = openai_payload(None, request);
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment,
+            self.api_version
+        );
+        Ok(client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("api-key", &self.api_key)
+            .json(&payload))
+    }
+
+    fn parse_event(&self, event: &SseEvent) -> Vec<StreamEvent> {
+        openai_parse_event(event)
+    }
+}
+
+/// Ollama (and any other local server) speaking the OpenAI-compatible chat completions API.
+#[derive(Debug)]
+pub struct OllamaProvider {
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl ChatProvider for OllamaProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Ollama
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn build_request(&self, client: &Client, request: &ChatRequest) -> Result<RequestBuilder> {
+        let payload = openai_payload(Some(&self.model), request);
+        let mut builder = client
+            .post(format!("{}/chat/completions", self.base_url.trim_end_matches('/')))
+            .header(header::CONTENT_TYPE, "application/json")
+            .json(&payload);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", api_key));
+        }
+        Ok(builder)
+    }
+
+    fn parse_event(&self, event: &SseEvent) -> Vec<StreamEvent> {
+        openai_parse_event(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct AnthropicProvider {
+    base_url: String,
+    api_key: String,
+    model: String,
+}
+
+impl ChatProvider for AnthropicProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Anthropic
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn build_request(&self, client: &Client, request: &ChatRequest) -> Result<RequestBuilder> {
+        // The messages API takes the system prompt as a top level field rather than a message
+        let system = request.messages.iter()
+            .filter(|msg| msg.role == Role::System)
+            .map(|msg| msg.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let mut messages: Vec<Value> = request.messages.iter()
+            .filter(|msg| msg.role == Role::User || msg.role == Role::Assistant)
+            .map(|msg| {
+                // Images go before the text, which is the order Anthropic recommends
+                let mut content: Vec<Value> = msg.images.iter().map(anthropic_image_block).collect();
+                content.push(json!({
+                    "type": "text",
+                    "text": msg.content,
+                }));
+                json!({
+                    "role": msg.role.to_string(),
+                    "content": content,
+                })
+            })
+            .collect();
+
+        for round in &request.tool_rounds {
+            let mut assistant_content = Vec::new();
+            if !round.assistant_text.is_empty() {
+                assistant_content.push(json!({ "type": "text", "text": round.assistant_text }));
+            }
+            for call in &round.calls {
+                let input: Value = serde_json::from_str(&call.arguments).unwrap_or_else(|_| json!({}));
+                assistant_content.push(json!({
+                    "type": "tool_use",
+                    "id": call.id,
+                    "name": call.name,
+                    "input": input,
+                }));
+            }
+            let results: Vec<Value> = round.results.iter().map(|result| {
+                let mut content = vec![json!({ "type": "text", "text": result.content })];
+                content.extend(result.image.iter().map(anthropic_image_block));
+                json!({
+                    "type": "tool_result",
+                    "tool_use_id": result.call_id,
+                    "content": content,
+                    "is_error": result.is_error,
+                })
+            }).collect();
+            messages.push(json!({ "role": "assistant", "content": assistant_content }));
+            messages.push(json!({ "role": "user", "content": results }));
+        }
+
+        let mut payload = json!({
+            "model": self.model,
+            "system": system,
+            "messages": messages,
+            "stream": true,
+            "max_tokens": request.params.max_tokens,
+        });
+        if let Some(temperature) = request.params.temperature {
+            payload["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = request.params.top_p {
+            payload["top_p"] = json!(top_p);
+        }
+        if !request.params.stop.is_empty() {
+            payload["stop_sequences"] = json!(request.params.stop);
+        }
+        if !request.tools.is_empty() {
+            payload["tools"] = request.tools.iter().map(|tool| json!({
+                "name": tool.name,
+                "description": tool.description,
+                "input_schema": tool.parameters,
+            })).collect();
+        }
+
+        Ok(client
+            .post(format!("{}/messages", self.base_url.trim_end_matches('/')))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&payload))
+    }
+
+    fn parse_event(&self, event: &SseEvent) -> Vec<StreamEvent> {
+        let Ok(value) = serde_json::from_str::<Value>(&event.data) else {
+            return vec![];
+        };
+        // The `type` in the body mirrors the SSE `event:` field
+        let event_type = event.event.as_deref().or(value["type"].as_str()).unwrap_or_default();
+
+        match event_type {
+            "message_start" => vec![StreamEvent::Usage(TokenUsage {
+                prompt_tokens: value["message"]["usage"]["input_tokens"].as_u64().map(|n| n as u32),
+                completion_tokens: None,
+            })],
+            "content_block_start" if value["content_block"]["type"] == "tool_use" => vec![StreamEvent::ToolCallDelta {
+                index: value["index"].as_u64().unwrap_or_default() as usize,
+                id: value["content_block"]["id"].as_str().map(str::to_owned),
+                name: value["content_block"]["name"].as_str().map(str::to_owned),
+                arguments: String::new(),
+            }],
+            "content_block_delta" => match value["delta"]["type"].as_str() {
+                Some("input_json_delta") => vec![StreamEvent::ToolCallDelta {
+                    index: value["index"].as_u64().unwrap_or_default() as usize,
+                    id: None,
+                    name: None,
+                    arguments: value["delta"]["partial_json"].as_str().unwrap_or_default().to_owned(),
+                }],
+                _ => value["delta"]["text"].as_str()
+                    .map(|text| vec![StreamEvent::Content(text.to_owned())])
+                    .unwrap_or_default(),
+            },
+            "message_delta" => {
+                let mut events = Vec::new();
+                if let Some(reason) = value["delta"]["stop_reason"].as_str() {
+                    events.push(StreamEvent::Finish(reason.to_owned()));
+                }
+                if let Some(output_tokens) = value["usage"]["output_tokens"].as_u64() {
+                    events.push(StreamEvent::Usage(TokenUsage {
+                        prompt_tokens: None,
+                        completion_tokens: Some(output_tokens as u32),
+                    }));
+                }
+                events
+            }
+            "message_stop" => vec![StreamEvent::Done],
+            "error" => vec![StreamEvent::Error(GptError::from_stream_error(&value))],
+            _ => vec![],
+        }
+    }
+}
+
+fn anthropic_image_block(image: &ImageAttachment) -> Value {
+    json!({
+        "type": "image",
+        "source": {
+            "type": "base64",
+            "media_type": image.media_type,
+            "data": image.data_base64,
+        }
+    })
+}
+
+fn openai_image_part(image: &ImageAttachment, detail: ImageDetail) -> Value {
+    json!({
+        "type": "image_url",
+        "image_url": {
+            "url": image.data_url(),
+            "detail": detail.as_str(),
+        }
+    })
+}
+
+fn openai_payload(model: Option<&str>, request: &ChatRequest) -> Value {
+    let mut messages: Vec<Value> = request.messages.iter()
+        // Function messages are the legacy form of tool results, which are sent from `tool_rounds` instead
+        .filter(|msg| msg.role != Role::Function)
+        .map(|msg| {
+            // Only user messages may carry images, everything else is sent as a plain string which
+            // OpenAI-compatible local servers understand as well
+            let content = if msg.role == Role::User && !msg.images.is_empty() {
+                let mut parts = vec![json!({ "type": "text", "text": msg.content })];
+                parts.extend(msg.images.iter().map(|image| openai_image_part(image, request.image_detail)));
+                Value::Array(parts)
+            } else {
+                json!(msg.content)
+            };
+            json!({
+                "role": msg.role.to_string(),
+                "content": content,
+            })
+        })
+        .collect();
+
+    for round in &request.tool_rounds {
+        let tool_calls: Vec<Value> = round.calls.iter().map(|call| json!({
+            "id": call.id,
+            "type": "function",
+            "function": {
+                "name": call.name,
+                "arguments": call.arguments,
+            }
+        })).collect();
+        messages.push(json!({
+            "role": "assistant",
+            "content": if round.assistant_text.is_empty() { Value::Null } else { json!(round.assistant_text) },
+            "tool_calls": tool_calls,
+        }));
+        for result in &round.results {
+            messages.push(json!({
+                "role": "tool",
+                "tool_call_id": result.call_id,
+                "content": result.content,
+            }));
+        }
+        // Tool messages can't contain images, so screenshots taken by a tool follow as a user message
+        let images: Vec<Value> = round.results.iter()
+            .filter_map(|result| result.image.as_ref())
+            .map(|image| openai_image_part(image, request.image_detail))
+            .collect();
+        if !images.is_empty() {
+            let mut parts = vec![json!({ "type": "text", "text": "The images returned by the tool calls above:" })];
+            parts.extend(images);
+            messages.push(json!({ "role": "user", "content": parts }));
+        }
+    }
+
+    let mut payload = json!({
+        "messages": messages,
+        "stream": true,
+        "max_tokens": request.params.max_tokens,
+    });
+    if let Some(model) = model {
+        payload["model"] = json!(model);
+    }
+    if let Some(temperature) = request.params.temperature {
+        payload["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = request.params.top_p {
+        payload["top_p"] = json!(top_p);
+    }
+    if !request.params.stop.is_empty() {
+        payload["stop"] = json!(request.params.stop);
+    }
+    if !request.tools.is_empty() {
+        payload["tools"] = request.tools.iter().map(|tool| json!({
+            "type": "function",
+            "function": {
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            }
+        })).collect();
+    }
+    payload
+}
+
+fn openai_parse_event(event: &SseEvent) -> Vec<StreamEvent> {
+    if event.data.trim() == "[DONE]" {
+        return vec![StreamEvent::Done];
+    }
+    let Ok(value) = serde_json::from_str::<Value>(&event.data) else {
+        return vec![];
+    };
+
+    if value["error"].is_object() {
+        return vec![StreamEvent::Error(GptError::from_stream_error(&value))];
+    }
+
+    let mut events = Vec::new();
+    if let Some(choice) = value["choices"].get(0) {
+        if let Some(content) = choice["delta"]["content"].as_str() {
+            events.push(StreamEvent::Content(content.to_owned()));
+        }
+        for tool_call in choice["delta"]["tool_calls"].as_array().into_iter().flatten() {
+            events.push(StreamEvent::ToolCallDelta {
+                index: tool_call["index"].as_u64().unwrap_or_default() as usize,
+                id: tool_call["id"].as_str().map(str::to_owned),
+                name: tool_call["function"]["name"].as_str().map(str::to_owned),
+                arguments: tool_call["function"]["arguments"].as_str().unwrap_or_default().to_owned(),
+            });
+        }
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            events.push(StreamEvent::Finish(reason.to_owned()));
+        }
+    }
+    // Only sent as the last chunk, and only when `stream_options.include_usage` was requested
+    if value["usage"].is_object() {
+        events.push(StreamEvent::Usage(TokenUsage {
+            prompt_tokens: value["usage"]["prompt_tokens"].as_u64().map(|n| n as u32),
+            completion_tokens: value["usage"]["completion_tokens"].as_u64().map(|n| n as u32),
+        }));
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use crate::cassette::{Cassette, Chunk, Interaction, Transport};
+    use crate::sse::SseDecoder;
+
+    #[test]
+    fn test_supports_tools() {
+        assert!(!supports_tools(ProviderKind::OpenAi, OPENAI_DEFAULT_MODEL));
+        assert!(!supports_tools(ProviderKind::Ollama, OLLAMA_DEFAULT_MODEL));
+        assert!(!supports_tools(ProviderKind::Ollama, "llama3.2-vision"));
+        assert!(supports_tools(ProviderKind::Ollama, "llama3.1:8b"));
+        assert!(supports_tools(ProviderKind::OpenAi, "gpt-4o-mini"));
+        assert!(supports_tools(ProviderKind::AzureOpenAi, "gpt-4"));
+        assert!(supports_tools(ProviderKind::Anthropic, ANTHROPIC_DEFAULT_MODEL));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_openai_stream() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo café\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":812,\"completion_tokens\":3}}\n\n",
+            "data: [DONE]\n\n",
+        ).as_bytes();
+        // Cut the body at awkward places: inside a JSON string, inside "é" and between "\n\n"
+        let inside_e_acute = body.iter().position(|&b| b == 0xC3).unwrap() + 1;
+        let cuts = [30, inside_e_acute, body.len() - 1, body.len()];
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for end in cuts {
+            chunks.push(Chunk::new(25, &body[start..end]));
+            start = end;
+        }
+        let transport = Transport::replay(Cassette {
+            interactions: vec![Interaction {
+                method: "POST".to_string(),
+                path: "/v1/chat/completions".to_string(),
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/event-stream".to_string())],
+                chunks,
+            }],
+        });
+
+        let request = transport.client().post(format!("{}/chat/completions", OPENAI_BASE_URL));
+        let mut response = transport.send(request).await.unwrap();
+        let mut decoder = SseDecoder::new();
+        let mut events = Vec::new();
+        while let Some(chunk) = response.body.next().await {
+            for event in decoder.push(&chunk.unwrap()) {
+                events.extend(openai_parse_event(&event));
+            }
+        }
+
+        assert_eq!(events, vec![
+            StreamEvent::Content("Hel".to_string()),
+            StreamEvent::Content("lo café".to_string()),
+            StreamEvent::Finish("stop".to_string()),
+            StreamEvent::Usage(TokenUsage { prompt_tokens: Some(812), completion_tokens: Some(3) }),
+            StreamEvent::Done,
+        ]);
+    }
+}
@@ -0,0 +1,381 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use cpal::{FromSample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::{error, info, warn};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use serde::Serialize;
+use serde_json::json;
+use tauri::{AppHandle, Manager};
+use crate::audio_utils::{StreamingResampler, TARGET_SAMPLE_RATE, trim_silence, VadSettings, VoiceActivityDetector};
+use crate::live_transcription::{LiveTranscription, local_transcription_enabled};
+
+/// How often the capture thread moves samples out of the ring buffer
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+/// Room in the ring buffer, far more than piles up between two drains
+const RING_BUFFER_SECONDS: usize = 2;
+/// How long opening the microphone may take before `start_recording` gives up
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mono audio captured from the microphone.
+#[derive(Debug, Clone)]
+pub struct AudioRecording {
+    pub audio_data: Vec<f32>,
+    pub config: StreamConfig,
+}
+
+impl AudioRecording {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.audio_data.len() as f64 / self.config.sample_rate.0 as f64)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingInfo {
+    pub duration_ms: u128,
+    pub sample_rate: u32,
+    /// Samples the audio thread couldn't fit in the ring buffer
+    pub dropped_samples: usize,
+    pub trigger: RecordingTrigger,
+}
+
+/// What started a recording, which decides what happens with the transcript when it stops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingTrigger {
+    /// The microphone button, the transcript is left in the input
+    #[default]
+    Button,
+    /// Held shortcut, the question is sent on release
+    HoldToTalk,
+    /// Shortcut pressed once to start and once to stop, like the button
+    ToggleToTalk,
+    /// Shortcut that asks about the screen once the question is spoken
+    AskAboutScreen,
+}
+
+impl RecordingTrigger {
+    /// The voice detection settings for this recording. Hold-to-talk only stops when the key is
+    /// released, so silence doesn't end it (it's still trimmed).
+    pub fn vad_settings(self, settings: Option<VadSettings>) -> Option<VadSettings> {
+        match self {
+            RecordingTrigger::HoldToTalk => settings.map(|settings| VadSettings { auto_stop_after: None, ..settings }),
+            _ => settings,
+        }
+    }
+}
+
+/// Averages each frame of interleaved samples into one.
+pub fn downmix(interleaved: &[f32], channels: u16) -> Vec<f32> {
+    match channels {
+        0 | 1 => interleaved.to_vec(),
+        channels => interleaved
+            .chunks_exact(channels as usize)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect(),
+    }
+}
+
+/// What happens to the audio while recording, besides keeping it.
+#[derive(Default)]
+pub struct CaptureOptions {
+    pub trigger: RecordingTrigger,
+    /// Gets the audio as it's converted, mono at `TARGET_SAMPLE_RATE`
+    pub listener: Option<Sender<Vec<f32>>>,
+    /// Detects speech to end the recording after silence and to trim it
+    pub vad: Option<VadSettings>,
+    /// Called on the recorder thread when silence ended the recording
+    pub on_auto_stop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Session {
+    stop: Sender<()>,
+    trigger: RecordingTrigger,
+    vad: Option<VadSettings>,
+    capture: JoinHandle<Result<AudioRecording>>,
+    dropped_samples: Arc<AtomicUsize>,
+}
+
+/// Records from the default input device. cpal streams can't move between threads, so each
+/// recording owns a capture thread that drains the audio thread's ring buffer until it's stopped.
+#[derive(Default)]
+pub struct Recorder {
+    session: Mutex<Option<Session>>,
+    last_recording: Mutex<Option<AudioRecording>>,
+}
+
+impl Recorder {
+    /// Starts recording and returns the device's format.
+    pub fn start(&self, options: CaptureOptions) -> Result<StreamConfig> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            bail!("Already recording");
+        }
+
+        let (stop, stop_receiver) = mpsc::channel();
+        let (ready, ready_receiver) = mpsc::channel();
+        let dropped_samples = Arc::new(AtomicUsize::new(0));
+        let dropped = dropped_samples.clone();
+        let (trigger, vad) = (options.trigger, options.vad.clone());
+        let capture = thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || capture(stop_receiver, ready, dropped, options))?;
+
+        let config = match ready_receiver.recv_timeout(START_TIMEOUT) {
+            Ok(Ok(config)) => config,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                // The thread notices the dropped sender once the device finally opens
+                bail!("The microphone didn't start in time");
+            }
+        };
+        *session = Some(Session { stop, trigger, vad, capture, dropped_samples });
+        Ok(config)
+    }
+
+    /// Stops recording and keeps the result for whoever takes it next.
+    pub fn stop(&self) -> Result<RecordingInfo> {
+        let session = self.session.lock().unwrap().take().ok_or_else(|| anyhow!("Not recording"))?;
+        let _ = session.stop.send(());
+        let mut recording = session.capture.join()
+            .map_err(|_| anyhow!("The recorder thread panicked"))??;
+        if let Some(vad) = &session.vad {
+            recording.audio_data = trim_silence(&recording.audio_data, recording.config.sample_rate.0, vad);
+        }
+        let info = RecordingInfo {
+            duration_ms: recording.duration().as_millis(),
+            sample_rate: recording.config.sample_rate.0,
+            dropped_samples: session.dropped_samples.load(Ordering::Relaxed),
+            trigger: session.trigger,
+        };
+        if info.dropped_samples > 0 {
+            warn!("Dropped {} samples while recording", info.dropped_samples);
+        }
+        *self.last_recording.lock().unwrap() = Some(recording);
+        Ok(info)
+    }
+
+    /// What started the current recording, None when not recording.
+    pub fn trigger(&self) -> Option<RecordingTrigger> {
+        self.session.lock().unwrap().as_ref().map(|session| session.trigger)
+    }
+
+    pub fn take_last_recording(&self) -> Option<AudioRecording> {
+        self.last_recording.lock().unwrap().take()
+    }
+}
+
+/// Runs on the recorder thread: opens the stream, then converts samples as they arrive until
+/// told to stop or until there was enough silence after speech.
+fn capture(
+    stop: Receiver<()>,
+    ready: Sender<Result<StreamConfig>>,
+    dropped: Arc<AtomicUsize>,
+    options: CaptureOptions,
+) -> Result<AudioRecording> {
+    let opened = open_input_stream(dropped).and_then(|(stream, consumer, config)| {
+        let resampler = StreamingResampler::new(config.sample_rate.0, config.channels)?;
+        Ok((stream, consumer, config, resampler))
+    });
+    let (stream, mut consumer, config, mut resampler) = match opened {
+        Ok(opened) => {
+            let _ = ready.send(Ok(opened.2.clone()));
+            opened
+        }
+        Err(e) => {
+            let _ = ready.send(Err(anyhow!("{:#}", e)));
+            return Err(e);
+        }
+    };
+
+    let CaptureOptions { mut listener, vad, on_auto_stop, .. } = options;
+    let mut vad = vad.map(|settings| VoiceActivityDetector::new(&settings, TARGET_SAMPLE_RATE as u32));
+    let mut samples = Vec::new();
+    let mut auto_stopped = false;
+    loop {
+        let mut stopping = !matches!(stop.recv_timeout(DRAIN_INTERVAL), Err(RecvTimeoutError::Timeout));
+        if !stopping && vad.as_ref().is_some_and(VoiceActivityDetector::should_stop) {
+            info!("Stopping the recording after silence");
+            stopping = true;
+            auto_stopped = true;
+        }
+        if stopping {
+            let _ = stream.pause();
+        }
+
+        let start = samples.len();
+        let drained: Vec<f32> = consumer.pop_iter().collect();
+        samples.extend(resampler.process(&drained)?);
+        if stopping {
+            samples.extend(resampler.flush()?);
+        }
+        if samples.len() > start {
+            if let Some(vad) = &mut vad {
+                vad.process(&samples[start..]);
+            }
+            // A listener that went away doesn't stop the recording
+            if listener.as_ref().is_some_and(|sender| sender.send(samples[start..].to_vec()).is_err()) {
+                listener = None;
+            }
+        }
+        if stopping {
+            break;
+        }
+    }
+    drop(stream);
+    drop(listener);
+    if auto_stopped {
+        if let Some(on_auto_stop) = on_auto_stop {
+            on_auto_stop();
+        }
+    }
+    Ok(AudioRecording {
+        audio_data: samples,
+        config: StreamConfig { channels: 1, sample_rate: SampleRate(TARGET_SAMPLE_RATE as u32), buffer_size: config.buffer_size },
+    })
+}
+
+fn open_input_stream(dropped: Arc<AtomicUsize>) -> Result<(Stream, HeapConsumer<f32>, StreamConfig)> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or_else(|| anyhow!("No microphone found"))?;
+    let supported = device.default_input_config().context("Failed to read the microphone's configuration")?;
+    let sample_format = supported.sample_format();
+    let config: StreamConfig = supported.into();
+    info!(
+        "Recording from {} at {} Hz, {} channel(s), {}",
+        device.name().unwrap_or_else(|_| "the default microphone".to_string()),
+        config.sample_rate.0,
+        config.channels,
+        sample_format
+    );
+
+    let capacity = config.sample_rate.0 as usize * config.channels as usize * RING_BUFFER_SECONDS;
+    let (producer, consumer) = HeapRb::<f32>::new(capacity).split();
+    let stream = match sample_format {
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, producer, dropped),
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, producer, dropped),
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, producer, dropped),
+        SampleFormat::I32 => build_input_stream::<i32>(&device, &config, producer, dropped),
+        other => bail!("Unsupported sample format {}", other),
+    }?;
+    stream.play().context("Failed to start the microphone")?;
+    Ok((stream, consumer, config))
+}
+
+/// The audio thread only converts samples and pushes them, it never blocks or allocates.
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut producer: HeapProducer<f32>,
+    dropped: Arc<AtomicUsize>,
+) -> Result<Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let mut overflow = 0;
+            for &sample in data {
+                if producer.push(f32::from_sample(sample)).is_err() {
+                    overflow += 1;
+                }
+            }
+            if overflow > 0 {
+                dropped.fetch_add(overflow, Ordering::Relaxed);
+            }
+        },
+        |e| error!("Microphone stream error: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Starts recording. With local transcription, the words arrive as `transcript` events while recording.
+#[tauri::command]
+pub fn start_recording(app_handle: AppHandle) -> Result<(), String> {
+    begin_recording(&app_handle, RecordingTrigger::Button).map_err(|e| format!("{:#}", e))
+}
+
+/// Starts recording for whatever `trigger` is, also used by the shortcuts.
+pub fn begin_recording(app_handle: &AppHandle, trigger: RecordingTrigger) -> Result<()> {
+    let (listener, audio) = if local_transcription_enabled(app_handle) {
+        let (listener, audio) = mpsc::channel();
+        (Some(listener), Some(audio))
+    } else {
+        (None, None)
+    };
+
+    let handle = app_handle.clone();
+    let on_auto_stop: Box<dyn FnOnce() + Send> = Box::new(move || {
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = stop_recording(handle).await {
+                error!("Failed to stop the recording after silence: {}", e);
+            }
+        });
+    });
+    let options = CaptureOptions {
+        trigger,
+        listener,
+        vad: trigger.vad_settings(VadSettings::from_store(app_handle)),
+        on_auto_stop: Some(on_auto_stop),
+    };
+    let device_config = app_handle.state::<Recorder>().start(options)?;
+    if let Some(audio) = audio {
+        app_handle.state::<LiveTranscription>().start(app_handle.clone(), audio);
+    }
+    info!("Started recording at {} Hz, {} channels ({:?})", device_config.sample_rate.0, device_config.channels, trigger);
+    let _ = app_handle.emit_all("recording_state", json!({ "recording": true, "trigger": trigger }));
+    Ok(())
+}
+
+/// Stops recording, after the last words were transcribed.
+#[tauri::command]
+pub async fn stop_recording(app_handle: AppHandle) -> Result<RecordingInfo, String> {
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let result = app_handle.state::<Recorder>().stop().map_err(|e| format!("{:#}", e));
+        app_handle.state::<LiveTranscription>().finish();
+        // The webview sends the question of hold-to-talk and ask-about-screen recordings
+        let trigger = result.as_ref().map(|info| info.trigger).ok();
+        let _ = app_handle.emit_all("recording_state", json!({ "recording": false, "trigger": trigger }));
+        result
+    }).await.map_err(|e| e.to_string())?;
+    let info = result?;
+    info!("Stopped recording after {} ms", info.duration_ms);
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downmix_and_stop_without_recording() {
+        assert_eq!(downmix(&[0.5, -0.5, 1.0, 0.0], 2), vec![0.0, 0.5]);
+        assert_eq!(downmix(&[0.5, -0.5], 1), vec![0.5, -0.5]);
+
+        let recorder = Recorder::default();
+        assert_eq!(recorder.trigger(), None);
+        assert!(recorder.stop().is_err());
+        assert!(recorder.take_last_recording().is_none());
+    }
+
+    #[test]
+    fn test_hold_to_talk_doesnt_stop_after_silence() {
+        let settings = VadSettings::default();
+        assert!(settings.auto_stop_after.is_some());
+        // Still trimmed
+        let hold_to_talk = VadSettings { auto_stop_after: None, ..settings.clone() };
+        assert_eq!(RecordingTrigger::HoldToTalk.vad_settings(Some(settings.clone())), Some(hold_to_talk));
+        assert_eq!(RecordingTrigger::HoldToTalk.vad_settings(None), None);
+        for trigger in [RecordingTrigger::Button, RecordingTrigger::ToggleToTalk, RecordingTrigger::AskAboutScreen] {
+            assert_eq!(trigger.vad_settings(Some(settings.clone())), Some(settings.clone()));
+        }
+    }
+}
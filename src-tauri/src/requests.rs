@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use futures::future::{AbortHandle, AbortRegistration};
+use log::info;
+use tauri::State;
+
+/// Keeps track of the GPT requests that are still streaming so they can be cancelled.
+#[derive(Debug, Default)]
+pub struct RequestRegistry {
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<String, AbortHandle>>,
+}
+
+impl RequestRegistry {
+    /// Registers a new request and returns its id. Any request that is still streaming is cancelled,
+    /// so the chunks of two answers can never interleave.
+    pub fn start(&self) -> (String, AbortRegistration) {
+        let id = format!("req-{}-{}", chrono::Utc::now().timestamp_millis(), self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (handle, registration) = AbortHandle::new_pair();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for (previous_id, previous) in in_flight.drain() {
+            info!("Cancelling request {} in favour of {}", previous_id, id);
+            previous.abort();
+        }
+        in_flight.insert(id.clone(), handle);
+        (id, registration)
+    }
+
+    pub fn finish(&self, id: &str) {
+        self.in_flight.lock().unwrap().remove(id);
+    }
+
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.in_flight.lock().unwrap().remove(id) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Stops a streaming answer. Returns false if the request had already finished.
+#[tauri::command]
+pub fn cancel_request(state: State<'_, RequestRegistry>, request_id: String) -> bool {
+    info!("Cancel requested for {}", request_id);
+    state.cancel(&request_id)
+}
@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use anyhow::{anyhow, bail, Result};
+use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+use global_hotkey::hotkey::HotKey;
+use log::{error, info};
+use serde_json::json;
+use tauri::{AppHandle, Manager};
+use tokio::sync::oneshot;
+use crate::live_transcription::local_transcription_enabled;
+use crate::recorder::{begin_recording, Recorder, RecordingTrigger, stop_recording};
+use crate::stores::get_string_from_store;
+use crate::{open_transcription_window, toggle_transcription_window};
+
+thread_local! {
+    /// Hotkeys can only be registered on the main thread, so the manager and what it registered
+    /// stay there
+    static REGISTERED: RefCell<Option<(GlobalHotKeyManager, Vec<HotKey>)>> = RefCell::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShortcutAction {
+    ToggleWindow,
+    /// Records while the key is held and sends the question on release
+    HoldToTalk,
+    /// Starts recording on one press and stops on the next, like the microphone button
+    ToggleToTalk,
+    /// Records a question about the screen, sent once it's spoken or on the next press
+    AskAboutScreen,
+}
+
+impl ShortcutAction {
+    const ALL: [ShortcutAction; 4] = [
+        ShortcutAction::ToggleWindow,
+        ShortcutAction::HoldToTalk,
+        ShortcutAction::ToggleToTalk,
+        ShortcutAction::AskAboutScreen,
+    ];
+
+    fn store_key(self) -> &'static str {
+        match self {
+            ShortcutAction::ToggleWindow => "shortcut_toggle_window",
+            ShortcutAction::HoldToTalk => "shortcut_hold_to_talk",
+            ShortcutAction::ToggleToTalk => "shortcut_toggle_to_talk",
+            ShortcutAction::AskAboutScreen => "shortcut_ask_about_screen",
+        }
+    }
+
+    fn default_binding(self) -> &'static str {
+        match self {
+            ShortcutAction::ToggleWindow => "F5",
+            ShortcutAction::HoldToTalk => "F6",
+            ShortcutAction::ToggleToTalk => "F7",
+            ShortcutAction::AskAboutScreen => "F8",
+        }
+    }
+
+    fn trigger(self) -> Option<RecordingTrigger> {
+        match self {
+            ShortcutAction::ToggleWindow => None,
+            ShortcutAction::HoldToTalk => Some(RecordingTrigger::HoldToTalk),
+            ShortcutAction::ToggleToTalk => Some(RecordingTrigger::ToggleToTalk),
+            ShortcutAction::AskAboutScreen => Some(RecordingTrigger::AskAboutScreen),
+        }
+    }
+}
+
+/// What a key event leads to.
+#[derive(Debug, PartialEq)]
+pub enum ShortcutCommand {
+    ToggleWindow,
+    StartRecording(RecordingTrigger),
+    StopRecording,
+    Nothing,
+}
+
+/// Decides what pressing or releasing the key of `action` does, `recording` being what started
+/// the current recording. Held keys repeat their press, which is ignored while recording.
+pub fn shortcut_command(action: ShortcutAction, state: HotKeyState, recording: Option<RecordingTrigger>) -> ShortcutCommand {
+    use ShortcutAction::*;
+    match (action, state, recording) {
+        (ToggleWindow, HotKeyState::Pressed, _) => ShortcutCommand::ToggleWindow,
+        (HoldToTalk, HotKeyState::Released, Some(RecordingTrigger::HoldToTalk)) => ShortcutCommand::StopRecording,
+        // Any recording stops when the toggle is pressed again, whatever started it
+        (ToggleToTalk, HotKeyState::Pressed, Some(_)) => ShortcutCommand::StopRecording,
+        (AskAboutScreen, HotKeyState::Pressed, Some(RecordingTrigger::AskAboutScreen)) => ShortcutCommand::StopRecording,
+        (action, HotKeyState::Pressed, None) => match action.trigger() {
+            Some(trigger) => ShortcutCommand::StartRecording(trigger),
+            None => ShortcutCommand::Nothing,
+        },
+        _ => ShortcutCommand::Nothing,
+    }
+}
+
+/// Which action each registered hotkey id stands for.
+#[derive(Default)]
+pub struct Shortcuts {
+    actions: Mutex<HashMap<u32, ShortcutAction>>,
+}
+
+/// Registers the shortcuts from the settings page (`shortcut_*`, empty for none) instead of the
+/// previous ones. Has to run on the main thread. Bindings that can't be registered are reported,
+/// the others still work.
+pub fn register_shortcuts(app_handle: &AppHandle) -> Result<()> {
+    REGISTERED.with(|registered| {
+        let mut registered = registered.borrow_mut();
+        if registered.is_none() {
+            *registered = Some((GlobalHotKeyManager::new()?, Vec::new()));
+        }
+        let (manager, hotkeys) = registered.as_mut().unwrap();
+        for hotkey in hotkeys.drain(..) {
+            manager.unregister(hotkey)?;
+        }
+
+        let mut actions = HashMap::new();
+        let mut failures = Vec::new();
+        for action in ShortcutAction::ALL {
+            let binding = get_string_from_store(app_handle, action.store_key())
+                .unwrap_or_else(|| action.default_binding().to_string());
+            if binding.trim().is_empty() {
+                continue;
+            }
+            let registered = binding.trim().parse::<HotKey>()
+                .map_err(|e| anyhow!("{}", e))
+                .and_then(|hotkey| manager.register(hotkey).map(|_| hotkey).map_err(|e| anyhow!("{}", e)));
+            match registered {
+                Ok(hotkey) => {
+                    actions.insert(hotkey.id(), action);
+                    hotkeys.push(hotkey);
+                }
+                Err(e) => failures.push(format!("{} ({:?}): {}", binding, action, e)),
+            }
+        }
+        info!("Registered {} shortcuts", actions.len());
+        *app_handle.state::<Shortcuts>().actions.lock().unwrap() = actions;
+        if !failures.is_empty() {
+            bail!("Couldn't register {}", failures.join(", "));
+        }
+        Ok(())
+    })
+}
+
+/// Handles hotkey events until the app exits.
+pub fn listen_for_shortcuts(app_handle: AppHandle) {
+    let spawned = thread::Builder::new().name("shortcuts".to_string()).spawn(move || {
+        while let Ok(event) = GlobalHotKeyEvent::receiver().recv() {
+            let action = app_handle.state::<Shortcuts>().actions.lock().unwrap().get(&event.id).copied();
+            if let Some(action) = action {
+                handle_shortcut(&app_handle, action, event.state);
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Failed to listen for shortcuts: {}", e);
+    }
+}
+
+fn handle_shortcut(app_handle: &AppHandle, action: ShortcutAction, state: HotKeyState) {
+    let recording = app_handle.state::<Recorder>().trigger();
+    match shortcut_command(action, state, recording) {
+        ShortcutCommand::ToggleWindow => toggle_transcription_window(app_handle),
+        ShortcutCommand::StartRecording(trigger) => {
+            // The Deepgram engine records in the webview, which shortcuts can't reach
+            let started = if local_transcription_enabled(app_handle) {
+                open_transcription_window(app_handle);
+                begin_recording(app_handle, trigger)
+            } else {
+                Err(anyhow!("Recording shortcuts need local transcription, pick Whisper and install its model on the settings page"))
+            };
+            if let Err(e) = started {
+                error!("Failed to start recording from a shortcut: {:#}", e);
+                let _ = app_handle.emit_all("recording_error", json!({ "message": format!("{:#}", e) }));
+            }
+        }
+        ShortcutCommand::StopRecording => {
+            let app_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = stop_recording(app_handle).await {
+                    error!("Failed to stop recording from a shortcut: {}", e);
+                }
+            });
+        }
+        ShortcutCommand::Nothing => {}
+    }
+}
+
+/// Registers the shortcuts again after they changed on the settings page.
+#[tauri::command]
+pub async fn apply_shortcuts(app_handle: AppHandle) -> Result<(), String> {
+    let (sender, receiver) = oneshot::channel();
+    let handle = app_handle.clone();
+    app_handle.run_on_main_thread(move || {
+        let _ = sender.send(register_shortcuts(&handle));
+    }).map_err(|e| e.to_string())?;
+    receiver.await.map_err(|e| e.to_string())?.map_err(|e| format!("{:#}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shortcut_command() {
+        use ShortcutAction::*;
+        let (pressed, released) = (HotKeyState::Pressed, HotKeyState::Released);
+
+        assert_eq!(shortcut_command(HoldToTalk, pressed, None), ShortcutCommand::StartRecording(RecordingTrigger::HoldToTalk));
+        // Key repeat while holding
+        assert_eq!(shortcut_command(HoldToTalk, pressed, Some(RecordingTrigger::HoldToTalk)), ShortcutCommand::Nothing);
+        assert_eq!(shortcut_command(HoldToTalk, released, Some(RecordingTrigger::HoldToTalk)), ShortcutCommand::StopRecording);
+        // Releasing doesn't stop what another shortcut started
+        assert_eq!(shortcut_command(HoldToTalk, released, Some(RecordingTrigger::Button)), ShortcutCommand::Nothing);
+
+        assert_eq!(shortcut_command(ToggleToTalk, pressed, None), ShortcutCommand::StartRecording(RecordingTrigger::ToggleToTalk));
+        assert_eq!(shortcut_command(ToggleToTalk, released, Some(RecordingTrigger::ToggleToTalk)), ShortcutCommand::Nothing);
+        assert_eq!(shortcut_command(ToggleToTalk, pressed, Some(RecordingTrigger::Button)), ShortcutCommand::StopRecording);
+
+        assert_eq!(shortcut_command(AskAboutScreen, pressed, None), ShortcutCommand::StartRecording(RecordingTrigger::AskAboutScreen));
+        assert_eq!(shortcut_command(AskAboutScreen, pressed, Some(RecordingTrigger::AskAboutScreen)), ShortcutCommand::StopRecording);
+        assert_eq!(shortcut_command(AskAboutScreen, pressed, Some(RecordingTrigger::HoldToTalk)), ShortcutCommand::Nothing);
+
+        assert_eq!(shortcut_command(ToggleWindow, pressed, Some(RecordingTrigger::Button)), ShortcutCommand::ToggleWindow);
+        assert_eq!(shortcut_command(ToggleWindow, released, None), ShortcutCommand::Nothing);
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use tauri::{AppHandle, Manager, State};
+use tokio::sync::oneshot;
+use tts::Tts;
+use crate::stores::{get_number_from_store, get_value_from_store};
+
+/// How often the speaker checks whether the current sentence has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Some voices take a moment to start, a sentence only counts as finished once it was heard or this has passed
+const START_TIMEOUT: Duration = Duration::from_secs(2);
+/// Words ending in a full stop that don't end a sentence
+const ABBREVIATIONS: [&str; 10] = ["e.g", "i.e", "etc", "vs", "mr", "mrs", "ms", "dr", "st", "no"];
+
+/// Splits a streamed markdown answer into sentences worth speaking. Fenced code is skipped and
+/// markdown syntax is removed, so a sentence can be spoken as soon as its last word has arrived.
+#[derive(Debug)]
+pub struct SentenceSplitter {
+    /// Text of the current line that hasn't been split yet
+    line: String,
+    /// Whether `line` starts at the start of a line, where a list marker or a code fence may be
+    at_line_start: bool,
+    in_code_block: bool,
+    /// Cleaned text of the sentence being built
+    sentence: String,
+}
+
+impl Default for SentenceSplitter {
+    fn default() -> Self {
+        Self { line: String::new(), at_line_start: true, in_code_block: false, sentence: String::new() }
+    }
+}
+
+impl SentenceSplitter {
+    /// Adds a delta and returns the sentences it completed.
+    pub fn push(&mut self, delta: &str) -> Vec<String> {
+        let mut sentences = Vec::new();
+        self.line.push_str(delta);
+        while let Some(newline) = self.line.find('\n') {
+            let line: String = self.line.drain(..=newline).collect();
+            self.take_text(line.trim_end(), &mut sentences);
+            // A line break ends a list item or a heading even without a full stop
+            self.flush(&mut sentences);
+            self.at_line_start = true;
+        }
+        if let Some(text) = self.cut_finished_sentences() {
+            self.take_text(&text, &mut sentences);
+            // The cut ends with a sentence
+            self.flush(&mut sentences);
+            self.at_line_start = false;
+        }
+        sentences
+    }
+
+    /// Returns whatever is left once the answer is complete.
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut sentences = Vec::new();
+        let line = std::mem::take(&mut self.line);
+        self.take_text(line.trim_end(), &mut sentences);
+        self.flush(&mut sentences);
+        sentences
+    }
+
+    /// The start of an unfinished line up to its last sentence end, once it's safe to split there.
+    fn cut_finished_sentences(&mut self) -> Option<String> {
+        if self.in_code_block {
+            return None;
+        }
+        if self.at_line_start {
+            // Wait until the line's marker is complete, and for the whole line if it may be a fence
+            let trimmed = self.line.trim_start();
+            if trimmed.starts_with('`') || !trimmed.contains(char::is_whitespace) {
+                return None;
+            }
+        }
+        let end = sentence_ends(&self.line).last().copied()?;
+        let text = &self.line[..end];
+        // A link can't be cleaned up until it's complete
+        if text.matches('[').count() != text.matches(']').count() || text.matches('(').count() != text.matches(')').count() {
+            return None;
+        }
+        Some(self.line.drain(..end).collect())
+    }
+
+    fn take_text(&mut self, text: &str, sentences: &mut Vec<String>) {
+        let mut text = text;
+        if self.at_line_start {
+            if text.trim_start().starts_with("```") {
+                self.in_code_block = !self.in_code_block;
+                self.flush(sentences);
+                return;
+            }
+            if self.in_code_block {
+                return;
+            }
+            text = strip_line_marker(text);
+        } else if self.in_code_block {
+            return;
+        }
+
+        let cleaned = clean_inline_markdown(text);
+        if cleaned.is_empty() {
+            return;
+        }
+        if !self.sentence.is_empty() {
+            self.sentence.push(' ');
+        }
+        self.sentence.push_str(&cleaned);
+
+        let mut start = 0;
+        for end in sentence_ends(&self.sentence) {
+            push_sentence(sentences, &self.sentence[start..end]);
+            start = end;
+        }
+        self.sentence.drain(..start);
+    }
+
+    fn flush(&mut self, sentences: &mut Vec<String>) {
+        push_sentence(sentences, &std::mem::take(&mut self.sentence));
+    }
+}
+
+fn push_sentence(sentences: &mut Vec<String>, text: &str) {
+    let text = text.trim();
+    // Punctuation or a lone emoji isn't worth speaking
+    if text.chars().any(char::is_alphanumeric) {
+        sentences.push(text.to_string());
+    }
+}
+
+/// Byte offsets just past each sentence end in `text`: a `.`, `!` or `?` (and any closing quotes
+/// or brackets) and the whitespace after it. Decimals, ellipses in the middle and common abbreviations
+/// aren't sentence ends.
+fn sentence_ends(text: &str) -> Vec<usize> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut ends = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (index, c) = chars[i];
+        if !matches!(c, '.' | '!' | '?' | '…') {
+            i += 1;
+            continue;
+        }
+        let mut next = i + 1;
+        while next < chars.len() && matches!(chars[next].1, '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’') {
+            next += 1;
+        }
+        let followed_by_space = next < chars.len() && chars[next].1.is_whitespace();
+        if followed_by_space && !(c == '.' && is_abbreviation(&text[..index])) {
+            ends.push(chars[next].0 + chars[next].1.len_utf8());
+        }
+        i = next;
+    }
+    ends
+}
+
+fn is_abbreviation(before: &str) -> bool {
+    let word = before.split_whitespace().last().unwrap_or_default().trim_start_matches('(').to_lowercase();
+    // Initials, like the J in "J. R. R. Tolkien"
+    (word.chars().count() == 1 && word.chars().all(char::is_alphabetic)) || ABBREVIATIONS.contains(&word.as_str())
+}
+
+/// Headings, quotes, list markers and horizontal rules at the start of a line.
+fn strip_line_marker(line: &str) -> &str {
+    let line = line.trim_start().trim_start_matches('#').trim_start_matches('>').trim_start();
+    if line.chars().all(|c| matches!(c, '-' | '*' | '_' | '=')) {
+        return "";
+    }
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(bullet) {
+            return item;
+        }
+    }
+    let digits = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(0);
+    let rest = &line[digits..];
+    if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")) {
+        return &rest[2..];
+    }
+    line
+}
+
+/// Links become their text, images are dropped, and emphasis, inline code and table markers go.
+fn clean_inline_markdown(text: &str) -> String {
+    let mut cleaned = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('[') {
+        let Some(close) = rest[open..].find("](").map(|i| open + i) else { break };
+        let Some(end) = rest[close..].find(')').map(|i| close + i) else { break };
+        if rest[..open].ends_with('!') {
+            cleaned.push_str(&rest[..open - 1]);
+        } else {
+            cleaned.push_str(&rest[..open]);
+            cleaned.push_str(&rest[open + 1..close]);
+        }
+        rest = &rest[end + 1..];
+    }
+    cleaned.push_str(rest);
+
+    cleaned.replace(['*', '`'], "").replace('|', " ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VoiceInfo {
+    pub id: String,
+    pub name: String,
+    pub language: String,
+}
+
+/// Voice, rate, pitch and volume from the settings page. Rate and pitch are multiples of the
+/// voice's normal rate and pitch, volume is a fraction of the loudest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeechSettings {
+    pub voice: Option<String>,
+    pub rate: f32,
+    pub pitch: f32,
+    pub volume: f32,
+}
+
+impl SpeechSettings {
+    pub fn from_store(app_handle: &AppHandle) -> Self {
+        let number = |key: &str, default: f32| get_number_from_store(app_handle, key).map_or(default, |n| n as f32);
+        Self {
+            voice: get_value_from_store(app_handle, "speech_voice")
+                .and_then(|value| value.as_str().map(str::to_owned))
+                .filter(|voice| !voice.is_empty()),
+            rate: number("speech_rate", 1.0).clamp(0.25, 4.0),
+            pitch: number("speech_pitch", 1.0).clamp(0.25, 4.0),
+            volume: number("speech_volume", 1.0).clamp(0.0, 1.0),
+        }
+    }
+}
+
+pub fn speech_enabled(app_handle: &AppHandle) -> bool {
+    get_value_from_store(app_handle, "speech_enabled")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(true)
+}
+
+enum SpeechCommand {
+    Configure(SpeechSettings),
+    Speak(String),
+    Stop,
+    Skip,
+    ListVoices(oneshot::Sender<Vec<VoiceInfo>>),
+}
+
+/// Speaks answers as they stream in. The synthesiser lives on its own thread, which speaks the
+/// queued sentences one at a time so that a single sentence can be skipped.
+#[derive(Debug)]
+pub struct Speaker {
+    commands: Mutex<Sender<SpeechCommand>>,
+    /// The request being spoken, and the sentences it hasn't finished yet
+    answer: Mutex<Option<(String, SentenceSplitter)>>,
+}
+
+impl Speaker {
+    pub fn new(app_handle: AppHandle) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || run_speaker(app_handle, receiver));
+        Self { commands: Mutex::new(sender), answer: Mutex::new(None) }
+    }
+
+    /// Stops whatever is being said and starts speaking the answer to `request_id`.
+    pub fn start_answer(&self, app_handle: &AppHandle, request_id: &str) {
+        self.send(SpeechCommand::Stop);
+        if !speech_enabled(app_handle) {
+            *self.answer.lock().unwrap() = None;
+            return;
+        }
+        self.send(SpeechCommand::Configure(SpeechSettings::from_store(app_handle)));
+        *self.answer.lock().unwrap() = Some((request_id.to_string(), SentenceSplitter::default()));
+    }
+
+    pub fn push_delta(&self, request_id: &str, delta: &str) {
+        let sentences = match &mut *self.answer.lock().unwrap() {
+            Some((id, splitter)) if id == request_id => splitter.push(delta),
+            _ => return,
+        };
+        sentences.into_iter().for_each(|sentence| self.send(SpeechCommand::Speak(sentence)));
+    }
+
+    /// Speaks the rest of the answer, which may not end with a full stop.
+    pub fn finish_answer(&self, request_id: &str) {
+        let mut answer = self.answer.lock().unwrap();
+        if !matches!(&*answer, Some((id, _)) if id == request_id) {
+            return;
+        }
+        if let Some((_, mut splitter)) = answer.take() {
+            splitter.finish().into_iter().for_each(|sentence| self.send(SpeechCommand::Speak(sentence)));
+        }
+    }
+
+    /// Stops speaking a cancelled answer, unless a newer one has already taken over.
+    pub fn cancel_answer(&self, request_id: &str) {
+        let mut answer = self.answer.lock().unwrap();
+        if matches!(&*answer, Some((id, _)) if id == request_id) {
+            *answer = None;
+            self.send(SpeechCommand::Stop);
+        }
+    }
+
+    pub fn stop(&self) {
+        *self.answer.lock().unwrap() = None;
+        self.send(SpeechCommand::Stop);
+    }
+
+    pub fn skip(&self) {
+        self.send(SpeechCommand::Skip);
+    }
+
+    fn send(&self, command: SpeechCommand) {
+        if self.commands.lock().unwrap().send(command).is_err() {
+            warn!("The speech thread has stopped");
+        }
+    }
+}
+
+fn run_speaker(app_handle: AppHandle, commands: Receiver<SpeechCommand>) {
+    let mut tts = match Tts::default() {
+        Ok(tts) => tts,
+        Err(e) => {
+            error!("Text-to-speech is unavailable: {}", e);
+            for command in commands {
+                if let SpeechCommand::ListVoices(reply) = command {
+                    let _ = reply.send(Vec::new());
+                }
+            }
+            return;
+        }
+    };
+
+    let mut queue: VecDeque<String> = VecDeque::new();
+    let mut speaking = false;
+    let mut heard = false;
+    let mut started_at = Instant::now();
+    let mut was_active = false;
+    loop {
+        match commands.recv_timeout(POLL_INTERVAL) {
+            Ok(SpeechCommand::Configure(settings)) => configure(&mut tts, &settings),
+            Ok(SpeechCommand::Speak(sentence)) => queue.push_back(sentence),
+            Ok(SpeechCommand::Stop) => {
+                queue.clear();
+                if speaking {
+                    let _ = tts.stop();
+                    speaking = false;
+                }
+            }
+            Ok(SpeechCommand::Skip) => {
+                if speaking {
+                    let _ = tts.stop();
+                    speaking = false;
+                }
+            }
+            Ok(SpeechCommand::ListVoices(reply)) => {
+                let _ = reply.send(voices(&tts));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if speaking {
+            let is_speaking = tts.is_speaking().unwrap_or(false);
+            heard |= is_speaking;
+            if !is_speaking && (heard || started_at.elapsed() > START_TIMEOUT) {
+                speaking = false;
+            }
+        }
+        if !speaking {
+            if let Some(sentence) = queue.pop_front() {
+                match tts.speak(sentence, false) {
+                    Ok(_) => {
+                        speaking = true;
+                        heard = false;
+                        started_at = Instant::now();
+                    }
+                    Err(e) => error!("Failed to speak: {}", e),
+                }
+            }
+        }
+
+        let active = speaking || !queue.is_empty();
+        if active != was_active {
+            was_active = active;
+            if let Err(e) = app_handle.emit_all("speech_state", json!({ "speaking": active })) {
+                error!("Failed to emit speech_state: {}", e);
+            }
+        }
+    }
+}
+
+fn configure(tts: &mut Tts, settings: &SpeechSettings) {
+    if let Some(voice_id) = &settings.voice {
+        match tts.voices().map(|voices| voices.into_iter().find(|voice| &voice.id() == voice_id)) {
+            Ok(Some(voice)) => {
+                if let Err(e) = tts.set_voice(&voice) {
+                    warn!("Failed to set voice {}: {}", voice_id, e);
+                }
+            }
+            Ok(None) => warn!("Voice {} not found, using the default", voice_id),
+            Err(e) => warn!("Failed to list voices: {}", e),
+        }
+    }
+    let rate = (tts.normal_rate() * settings.rate).clamp(tts.min_rate(), tts.max_rate());
+    let pitch = (tts.normal_pitch() * settings.pitch).clamp(tts.min_pitch(), tts.max_pitch());
+    let volume = tts.min_volume() + (tts.max_volume() - tts.min_volume()) * settings.volume;
+    if let Err(e) = tts.set_rate(rate).and_then(|tts| tts.set_pitch(pitch)).and_then(|tts| tts.set_volume(volume)) {
+        warn!("Failed to apply speech settings: {}", e);
+    }
+}
+
+fn voices(tts: &Tts) -> Vec<VoiceInfo> {
+    match tts.voices() {
+        Ok(voices) => voices.into_iter()
+            .map(|voice| VoiceInfo { id: voice.id(), name: voice.name(), language: voice.language().to_string() })
+            .collect(),
+        Err(e) => {
+            warn!("Failed to list voices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[tauri::command]
+pub fn stop_speaking(speaker: State<'_, Speaker>) {
+    info!("Stopping speech");
+    speaker.stop();
+}
+
+#[tauri::command]
+pub fn skip_sentence(speaker: State<'_, Speaker>) {
+    speaker.skip();
+}
+
+#[tauri::command]
+pub async fn list_voices(speaker: State<'_, Speaker>) -> Result<Vec<VoiceInfo>, String> {
+    let (sender, receiver) = oneshot::channel();
+    speaker.send(SpeechCommand::ListVoices(sender));
+    receiver.await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splits_streamed_markdown_into_sentences() {
+        let answer = "## Fixing it\nOpen **Settings**, e.g. with ⌘, and pick [Privacy](https://example.com). Then restart the app!\n\n\
+            ```bash\nkillall Dock\n```\n1. Run `cargo build` 3.5 times\n- Done";
+        let mut splitter = SentenceSplitter::default();
+        let mut sentences = Vec::new();
+        // The first sentence is ready before the rest of its line has arrived
+        let split_at = answer.find("Then").unwrap() + 2;
+        sentences.extend(splitter.push(&answer[..split_at]));
+        assert_eq!(sentences, vec!["Fixing it", "Open Settings, e.g. with ⌘, and pick Privacy."]);
+
+        for chunk in answer.as_bytes()[split_at..].chunks(3) {
+            sentences.extend(splitter.push(std::str::from_utf8(chunk).unwrap()));
+        }
+        sentences.extend(splitter.finish());
+        assert_eq!(sentences[2..], [
+            "Then restart the app!".to_string(),
+            "Run cargo build 3.5 times".to_string(),
+            "Done".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,182 @@
+// Incremental decoder for Server-Sent Events (https://html.spec.whatwg.org/multipage/server-sent-events.html)
+// Bytes are buffered until a full line is available, so network chunks can split lines and multi-byte
+// UTF-8 characters anywhere without corrupting the decoded events.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `event:` field, `None` for the default "message" type
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    // Set when the last chunk ended in '\r', so a '\n' at the start of the next one belongs to the same line ending
+    pending_cr: bool,
+    started: bool,
+    event_type: Option<String>,
+    data: String,
+    has_data: bool,
+    last_event_id: Option<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a network chunk into the decoder and returns every event completed by it.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        let mut line_start = 0;
+
+        let mut bytes = chunk;
+        if self.pending_cr {
+            self.pending_cr = false;
+            if bytes.first() == Some(&b'\n') {
+                bytes = &bytes[1..];
+            }
+        }
+
+        // Only the new bytes are scanned, anything before them in the buffer is known not to contain a line ending
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            if byte != b'\n' && byte != b'\r' {
+                i += 1;
+                continue;
+            }
+            self.buffer.extend_from_slice(&bytes[line_start..i]);
+            let line = std::mem::take(&mut self.buffer);
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+
+            i += 1;
+            if byte == b'\r' {
+                match bytes.get(i) {
+                    Some(b'\n') => i += 1,
+                    None => self.pending_cr = true,
+                    _ => {}
+                }
+            }
+            line_start = i;
+        }
+        self.buffer.extend_from_slice(&bytes[line_start..]);
+
+        events
+    }
+
+    /// Flushes a final event that wasn't followed by a blank line before the stream ended.
+    /// The spec discards these, but some OpenAI-compatible servers close the stream without the blank line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            if let Some(event) = self.process_line(&line) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        let mut line = String::from_utf8_lossy(line).into_owned();
+        if !self.started {
+            self.started = true;
+            if let Some(stripped) = line.strip_prefix('\u{feff}') {
+                line = stripped.to_owned();
+            }
+        }
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // Comment, used by some servers as a keep-alive
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+
+        match field {
+            "event" => self.event_type = Some(value.to_owned()),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_owned()),
+            // "retry" only matters for EventSource reconnection, and unknown fields are ignored
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event_type = self.event_type.take();
+        if !self.has_data {
+            return None;
+        }
+        self.has_data = false;
+        Some(SseEvent {
+            event: event_type,
+            data: std::mem::take(&mut self.data),
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<SseEvent> = chunks.iter().flat_map(|chunk| decoder.push(chunk)).collect();
+        events.extend(decoder.finish());
+        events
+    }
+
+    #[test]
+    fn test_decodes_openai_style_stream() {
+        let events = decode_all(&[b"data: {\"a\":1}\n\ndata: [DONE]\n\n"]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, "{\"a\":1}");
+        assert_eq!(events[1].data, "[DONE]");
+    }
+
+    #[test]
+    fn test_braces_inside_strings_do_not_matter() {
+        let events = decode_all(&[b"data: {\"content\":\"fn main() {\"}\n\n"]);
+        assert_eq!(events[0].data, "{\"content\":\"fn main() {\"}");
+    }
+
+    #[test]
+    fn test_multibyte_character_split_across_chunks() {
+        let bytes = "data: caf\u{e9} \u{1f600}\n\n".as_bytes();
+        // Split inside both the two byte and the four byte sequence
+        let events = decode_all(&[&bytes[..10], &bytes[10..13], &bytes[13..]]);
+        assert_eq!(events[0].data, "caf\u{e9} \u{1f600}");
+    }
+
+    #[test]
+    fn test_event_fields_comments_and_crlf() {
+        let events = decode_all(&[b": keep-alive\r\nevent: content_block_delta\r", b"\ndata: one\r\ndata: two\r\n\r\n"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.as_deref(), Some("content_block_delta"));
+        assert_eq!(events[0].data, "one\ntwo");
+    }
+
+    #[test]
+    fn test_unterminated_event_is_flushed() {
+        let events = decode_all(&[b"data: partial"]);
+        assert_eq!(events[0].data, "partial");
+    }
+}
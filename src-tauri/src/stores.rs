@@ -1,5 +1,5 @@
 use std::path::PathBuf;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use serde_json::Value;
 use tauri::{AppHandle, Manager, Wry};
 use tauri_plugin_store::{StoreCollection, with_store};
@@ -17,10 +17,11 @@ pub fn get_from_store(handle: &AppHandle, key: &str) -> Option<String>{
 
     with_store(handle.clone(), stores, path, |store| {
         if let Some(stored_value) = store.get(key) {
-            info!("Retrieved value from store: {}", stored_value);
+            // Never the value itself, settings can hold credentials
+            debug!("Retrieved '{}' from store", key);
             retrieved = Some(stored_value.to_string());
         } else {
-            warn!("Key not found in store");
+            warn!("Key '{}' not found in store", key);
         }
         Ok(())
     }).expect("Failed to interact with the store");
@@ -45,4 +46,36 @@ pub fn set_in_store(handle: &AppHandle, key: String, value: Value) {
         }
         Ok(())
     }).expect("Failed to interact with the store");
-}
\ No newline at end of file
+}
+
+/// Removes a key and saves right away, so the value is gone from disk too.
+pub fn delete_from_store(handle: &AppHandle, key: &str) {
+    let stores = handle.state::<StoreCollection<Wry>>();
+    let path = PathBuf::from(".settings.dat");
+
+    with_store(handle.clone(), stores, path, |store| {
+        store.delete(key)?;
+        store.save()
+    }).unwrap_or_else(|e| error!("Failed to delete key '{}' from store: {}", key, e));
+}
+
+/// Like `get_from_store`, but parses the stored JSON back into a `Value`.
+pub fn get_value_from_store(handle: &AppHandle, key: &str) -> Option<Value> {
+    get_from_store(handle, key).and_then(|stored| serde_json::from_str(&stored).ok())
+}
+
+/// Like `get_from_store`, but for settings saved as plain strings: returns the string itself
+/// rather than its JSON representation (i.e. without the surrounding quotes).
+pub fn get_string_from_store(handle: &AppHandle, key: &str) -> Option<String> {
+    get_value_from_store(handle, key).and_then(|value| value.as_str().map(str::to_owned))
+}
+
+/// Like `get_from_store`, but for numbers. Number inputs save what was typed as a string (an empty
+/// one once cleared), so strings are parsed too.
+pub fn get_number_from_store(handle: &AppHandle, key: &str) -> Option<f64> {
+    get_value_from_store(handle, key).and_then(|value| match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    })
+}
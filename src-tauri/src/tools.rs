@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::Command;
+use anyhow::{anyhow, bail, Result};
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{AppHandle, ClipboardManager, Manager};
+use tokio::sync::oneshot;
+use crate::images::ImageAttachment;
+use crate::screenshot::screenshot;
+use crate::stores::{get_value_from_store, set_in_store};
+
+const TOOL_APPROVALS_STORE_KEY: &str = "tool_approvals";
+
+/// How much the user has to be involved before a tool the model asked for is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalPolicy {
+    /// Run without asking
+    Auto,
+    /// Ask for confirmation every time
+    Ask,
+    /// Never run, and don't advertise the tool to the model
+    Never,
+}
+
+/// A tool as advertised to the model.
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object
+    pub parameters: Value,
+}
+
+/// A tool call requested by the model, assembled from the streamed deltas.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// The raw JSON arguments, as generated by the model
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolResult {
+    pub call_id: String,
+    pub content: String,
+    pub is_error: bool,
+    pub image: Option<ImageAttachment>,
+}
+
+/// One round trip of tool use: what the assistant said and asked for, and what the tools returned.
+#[derive(Debug, Clone)]
+pub struct ToolRound {
+    pub assistant_text: String,
+    pub calls: Vec<ToolCall>,
+    pub results: Vec<ToolResult>,
+}
+
+#[derive(Debug, Default)]
+pub struct ToolOutput {
+    pub text: String,
+    /// An image for the model to look at, e.g. a fresh screenshot
+    pub image: Option<ImageAttachment>,
+}
+
+impl ToolOutput {
+    fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            image: None,
+        }
+    }
+}
+
+type ToolHandler = Box<dyn Fn(AppHandle, Value) -> BoxFuture<'static, Result<ToolOutput>> + Send + Sync>;
+
+pub struct Tool {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: Value,
+    pub default_policy: ApprovalPolicy,
+    handler: ToolHandler,
+}
+
+impl Tool {
+    pub fn new<F>(name: &'static str, description: &'static str, parameters: Value, default_policy: ApprovalPolicy, handler: F) -> Self
+    where
+        F: Fn(AppHandle, Value) -> BoxFuture<'static, Result<ToolOutput>> + Send + Sync + 'static,
+    {
+        Self {
+            name,
+            description,
+            parameters,
+            default_policy,
+            handler: Box::new(handler),
+        }
+    }
+}
+
+impl std::fmt::Debug for Tool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tool").field("name", &self.name).finish()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_default_tools() -> Self {
+        let mut registry = Self::new();
+        registry.register(Tool::new(
+            "take_screenshot",
+            "Take a fresh screenshot of the user's screen, e.g. after something on it has changed.",
+            json!({ "type": "object", "properties": {} }),
+            ApprovalPolicy::Ask,
+            |_, _| Box::pin(take_screenshot_tool()),
+        ));
+        registry.register(Tool::new(
+            "read_clipboard",
+            "Read the text currently on the user's clipboard.",
+            json!({ "type": "object", "properties": {} }),
+            ApprovalPolicy::Ask,
+            |app_handle, _| Box::pin(read_clipboard_tool(app_handle)),
+        ));
+        registry.register(Tool::new(
+            "open_url",
+            "Open a http(s) or mailto URL in the user's default application.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "url": { "type": "string", "description": "The URL to open" }
+                },
+                "required": ["url"]
+            }),
+            ApprovalPolicy::Ask,
+            |app_handle, args| Box::pin(open_url_tool(app_handle, args)),
+        ));
+        registry.register(Tool::new(
+            "create_reminder",
+            "Create a reminder in the user's Reminders app.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "title": { "type": "string", "description": "What to be reminded of" },
+                    "notes": { "type": "string", "description": "Optional extra details" },
+                    "due": { "type": "string", "description": "Optional local due date and time, formatted as YYYY-MM-DDTHH:MM" }
+                },
+                "required": ["title"]
+            }),
+            ApprovalPolicy::Ask,
+            |_, args| Box::pin(create_reminder_tool(args)),
+        ));
+        registry
+    }
+
+    pub fn register(&mut self, tool: Tool) {
+        self.tools.retain(|existing| existing.name != tool.name);
+        self.tools.push(tool);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.name == name)
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    /// The tools to advertise to the model, leaving out the ones the user has disabled.
+    pub fn specs(&self, app_handle: &AppHandle) -> Vec<ToolSpec> {
+        self.specs_for(|tool| approval_policy(app_handle, tool))
+    }
+
+    fn specs_for(&self, policy: impl Fn(&Tool) -> ApprovalPolicy) -> Vec<ToolSpec> {
+        self.tools.iter()
+            .filter(|tool| policy(tool) != ApprovalPolicy::Never)
+            .map(|tool| ToolSpec {
+                name: tool.name.to_string(),
+                description: tool.description.to_string(),
+                parameters: tool.parameters.clone(),
+            })
+            .collect()
+    }
+
+    /// Runs a tool call after checking its approval policy. Failures are returned to the model as
+    /// error results rather than aborting the answer.
+    pub async fn execute(&self, app_handle: &AppHandle, call: &ToolCall) -> ToolResult {
+        let result = self.try_execute(app_handle, call).await;
+        match result {
+            Ok(output) => ToolResult { call_id: call.id.clone(), content: output.text, is_error: false, image: output.image },
+            Err(e) => {
+                warn!("Tool call {} failed: {}", call.name, e);
+                ToolResult { call_id: call.id.clone(), content: e.to_string(), is_error: true, image: None }
+            }
+        }
+    }
+
+    async fn try_execute(&self, app_handle: &AppHandle, call: &ToolCall) -> Result<ToolOutput> {
+        let tool = self.get(&call.name).ok_or_else(|| anyhow!("Unknown tool: {}", call.name))?;
+        let args: Value = if call.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(&call.arguments).map_err(|e| anyhow!("Invalid arguments for {}: {}", call.name, e))?
+        };
+
+        check_approval(tool.name, approval_policy(app_handle, tool), || ask_for_approval(app_handle, tool, &args)).await?;
+
+        info!("Running tool {}", tool.name);
+        (tool.handler)(app_handle.clone(), args).await
+    }
+}
+
+/// Collects the streamed tool call fragments (keyed by their index in the response) into complete calls.
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    calls: Vec<(usize, ToolCall)>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(&mut self, index: usize, id: Option<String>, name: Option<String>, arguments: &str) {
+        let position = match self.calls.iter().position(|(i, _)| *i == index) {
+            Some(position) => position,
+            None => {
+                self.calls.push((index, ToolCall::default()));
+                self.calls.len() - 1
+            }
+        };
+        let call = &mut self.calls[position].1;
+        if let Some(id) = id {
+            call.id = id;
+        }
+        if let Some(name) = name {
+            call.name.push_str(&name);
+        }
+        call.arguments.push_str(arguments);
+    }
+
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls.into_iter()
+            .map(|(index, mut call)| {
+                if call.id.is_empty() {
+                    // Some OpenAI-compatible servers leave out the id, but the result has to reference one
+                    call.id = format!("call_{}", index);
+                }
+                call
+            })
+            .collect()
+    }
+}
+
+/// Fails unless `policy` lets the tool run, calling `ask` only when the user has to confirm.
+async fn check_approval<F, Fut>(tool_name: &str, policy: ApprovalPolicy, ask: F) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    match policy {
+        ApprovalPolicy::Auto => Ok(()),
+        ApprovalPolicy::Never => bail!("The user has disabled the {} tool", tool_name),
+        ApprovalPolicy::Ask => {
+            if !ask().await {
+                bail!("The user declined the {} tool call", tool_name);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn approval_policy(app_handle: &AppHandle, tool: &Tool) -> ApprovalPolicy {
+    get_value_from_store(app_handle, TOOL_APPROVALS_STORE_KEY)
+        .and_then(|approvals| approvals.get(tool.name).cloned())
+        .and_then(|policy| serde_json::from_value(policy).ok())
+        .unwrap_or(tool.default_policy)
+}
+
+async fn ask_for_approval(app_handle: &AppHandle, tool: &Tool, args: &Value) -> bool {
+    let (sender, receiver) = oneshot::channel();
+    let message = format!("Derby wants to use the {} tool with:\n{}\n\nAllow it?", tool.name, args);
+    let window = app_handle.get_window("transcription_window");
+    tauri::api::dialog::ask(window.as_ref(), "Allow tool use?", message, move |approved| {
+        let _ = sender.send(approved);
+    });
+    receiver.await.unwrap_or(false)
+}
+
+async fn take_screenshot_tool() -> Result<ToolOutput> {
+    let path = std::env::temp_dir().join("derby_tool_screenshot.png");
+    let path = tokio::task::spawn_blocking(move || screenshot(path)).await??;
+    Ok(ToolOutput {
+        text: "Took a new screenshot of the user's screen.".to_string(),
+        image: Some(ImageAttachment::from_file(&path).await?),
+    })
+}
+
+async fn read_clipboard_tool(app_handle: AppHandle) -> Result<ToolOutput> {
+    let text = app_handle.clipboard_manager().read_text()?;
+    Ok(ToolOutput::text(text.unwrap_or_else(|| "The clipboard is empty.".to_string())))
+}
+
+async fn open_url_tool(app_handle: AppHandle, args: Value) -> Result<ToolOutput> {
+    let url = args["url"].as_str().ok_or_else(|| anyhow!("Missing url"))?;
+    let parsed = reqwest::Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https" | "mailto") {
+        bail!("Only http(s) and mailto URLs can be opened");
+    }
+    tauri::api::shell::open(&app_handle.shell_scope(), url, None)?;
+    Ok(ToolOutput::text(format!("Opened {}", url)))
+}
+
+async fn create_reminder_tool(args: Value) -> Result<ToolOutput> {
+    let title = args["title"].as_str().ok_or_else(|| anyhow!("Missing title"))?;
+    let notes = args["notes"].as_str().unwrap_or_default();
+    let due = args["due"].as_str()
+        .map(|due| NaiveDateTime::parse_from_str(due, "%Y-%m-%dT%H:%M"))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid due date: {}", e))?;
+
+    let mut script = String::new();
+    let mut properties = format!("name:\"{}\", body:\"{}\"", applescript_escape(title), applescript_escape(notes));
+    if let Some(due) = due {
+        // AppleScript date literals depend on the system locale, so the date is built field by field
+        script.push_str(&format!(
+            "set dueDate to current date\nset day of dueDate to 1\nset year of dueDate to {}\nset month of dueDate to {}\nset day of dueDate to {}\nset time of dueDate to {}\n",
+            due.year(), due.month(), due.day(), due.hour() * 3600 + due.minute() * 60
+        ));
+        properties.push_str(", remind me date:dueDate");
+    }
+    script.push_str(&format!("tell application \"Reminders\" to make new reminder with properties {{{}}}", properties));
+
+    let output = tokio::task::spawn_blocking(move || Command::new("osascript").arg("-e").arg(script).output()).await??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("Failed to create reminder: {}", stderr);
+        bail!("Failed to create reminder: {}", stderr.trim());
+    }
+    Ok(ToolOutput::text(format!("Created the reminder \"{}\"", title)))
+}
+
+fn applescript_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[derive(Debug, Serialize)]
+pub struct ToolSetting {
+    name: &'static str,
+    description: &'static str,
+    policy: ApprovalPolicy,
+}
+
+#[tauri::command]
+pub fn get_tool_settings(app_handle: AppHandle) -> Vec<ToolSetting> {
+    ToolRegistry::with_default_tools().tools().iter()
+        .map(|tool| ToolSetting {
+            name: tool.name,
+            description: tool.description,
+            policy: approval_policy(&app_handle, tool),
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub fn set_tool_approval(app_handle: AppHandle, name: String, policy: ApprovalPolicy) -> Result<(), String> {
+    if ToolRegistry::with_default_tools().get(&name).is_none() {
+        return Err(format!("Unknown tool: {}", name));
+    }
+    let mut approvals: HashMap<String, Value> = get_value_from_store(&app_handle, TOOL_APPROVALS_STORE_KEY)
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default();
+    approvals.insert(name, json!(policy));
+    set_in_store(&app_handle, TOOL_APPROVALS_STORE_KEY.to_string(), json!(approvals));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_tool_call_accumulator() {
+        let mut accumulator = ToolCallAccumulator::default();
+        // Two calls streamed interleaved, the name and arguments split across deltas
+        accumulator.push(0, Some("call_abc".to_string()), Some("open_".to_string()), "");
+        accumulator.push(1, None, Some("take_screenshot".to_string()), "");
+        accumulator.push(0, None, Some("url".to_string()), "{\"url\":");
+        accumulator.push(1, None, None, "{}");
+        accumulator.push(0, None, None, " \"https://example.com\"}");
+
+        assert_eq!(accumulator.finish(), vec![
+            ToolCall { id: "call_abc".to_string(), name: "open_url".to_string(), arguments: "{\"url\": \"https://example.com\"}".to_string() },
+            // No id was streamed for the second call
+            ToolCall { id: "call_1".to_string(), name: "take_screenshot".to_string(), arguments: "{}".to_string() },
+        ]);
+        assert!(ToolCallAccumulator::default().finish().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_approval() {
+        let asked = AtomicBool::new(false);
+        let ask = |answer: bool| {
+            let asked = &asked;
+            move || async move {
+                asked.store(true, Ordering::SeqCst);
+                answer
+            }
+        };
+
+        assert!(check_approval("take_screenshot", ApprovalPolicy::Auto, ask(false)).await.is_ok());
+        assert!(!asked.load(Ordering::SeqCst));
+        let error = check_approval("open_url", ApprovalPolicy::Never, ask(true)).await.unwrap_err();
+        assert_eq!(error.to_string(), "The user has disabled the open_url tool");
+        assert!(!asked.load(Ordering::SeqCst));
+
+        assert!(check_approval("open_url", ApprovalPolicy::Ask, ask(true)).await.is_ok());
+        assert!(asked.swap(false, Ordering::SeqCst));
+        let error = check_approval("open_url", ApprovalPolicy::Ask, ask(false)).await.unwrap_err();
+        assert_eq!(error.to_string(), "The user declined the open_url tool call");
+        assert!(asked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_disabled_tools_are_not_advertised() {
+        let registry = ToolRegistry::with_default_tools();
+        let names = |specs: Vec<ToolSpec>| specs.into_iter().map(|spec| spec.name).collect::<Vec<_>>();
+
+        assert_eq!(names(registry.specs_for(|tool| tool.default_policy)), vec!["take_screenshot", "read_clipboard", "open_url", "create_reminder"]);
+        let specs = registry.specs_for(|tool| if tool.name == "read_clipboard" { ApprovalPolicy::Never } else { ApprovalPolicy::Ask });
+        assert_eq!(names(specs), vec!["take_screenshot", "open_url", "create_reminder"]);
+    }
+
+    #[test]
+    fn test_default_tools_ask_first() {
+        // Running a tool without asking is something the user opts in to on the settings page
+        for tool in &ToolRegistry::with_default_tools().tools {
+            assert_eq!(tool.default_policy, ApprovalPolicy::Ask, "{}", tool.name);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{Datelike, Local, NaiveDate};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, State};
+use crate::images::ImageAttachment;
+use crate::providers::{ChatRequest, ImageDetail, ProviderKind, TokenUsage};
+use crate::stores::{get_string_from_store, get_value_from_store};
+
+/// USD per million prompt and completion tokens, matched by model name prefix (longest prefix first).
+const MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4-vision-preview", 10.00, 30.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4-32k", 60.00, 120.00),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("claude-3-5-sonnet", 3.00, 15.00),
+    ("claude-3-opus", 15.00, 75.00),
+    ("claude-3-sonnet", 3.00, 15.00),
+    ("claude-3-haiku", 0.25, 1.25),
+    // Memory embeddings, which have no completion
+    ("text-embedding-3-small", 0.02, 0.0),
+];
+
+/// Rough token estimate for text: about four characters per token for English.
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32 + 3) / 4
+}
+
+/// Tokens the provider charges for an image of the given size.
+pub fn estimate_image_tokens(provider: ProviderKind, detail: ImageDetail, width: u32, height: u32) -> u32 {
+    match provider {
+        // Anthropic scales images down to a 1568px long edge and charges about one token per 750 pixels
+        ProviderKind::Anthropic => {
+            let scale = (1568.0 / width.max(height) as f64).min(1.0);
+            let pixels = (width as f64 * scale) * (height as f64 * scale);
+            (pixels / 750.0).ceil() as u32
+        }
+        _ => openai_image_tokens(detail, width, height),
+    }
+}
+
+/// OpenAI's vision pricing: a flat 85 tokens at low detail. At high detail the image is fit into
+/// 2048x2048, its short side scaled to 768px, and every 512px tile costs another 170 tokens.
+fn openai_image_tokens(detail: ImageDetail, width: u32, height: u32) -> u32 {
+    if detail == ImageDetail::Low {
+        return 85;
+    }
+    let (mut w, mut h) = (width as f64, height as f64);
+    let fit = (2048.0 / w.max(h)).min(1.0);
+    w *= fit;
+    h *= fit;
+    let shortest = (768.0 / w.min(h)).min(1.0);
+    w *= shortest;
+    h *= shortest;
+    let tiles = (w / 512.0).ceil() as u32 * (h / 512.0).ceil() as u32;
+    85 + 170 * tiles
+}
+
+/// Reads the size from the IHDR chunk of a base64 encoded PNG.
+pub fn png_dimensions(image_base64: &str) -> Option<(u32, u32)> {
+    // 24 bytes (signature, chunk length and type, width, height) are exactly 32 base64 characters
+    let header = general_purpose::STANDARD.decode(image_base64.get(..32)?).ok()?;
+    if &header[..8] != b"\x89PNG\r\n\x1a\n" {
+        return None;
+    }
+    let width = u32::from_be_bytes(header[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(header[20..24].try_into().ok()?);
+    Some((width, height))
+}
+
+/// Estimated prompt tokens of a request: text, per-message overhead and the attached images.
+pub fn estimate_prompt_tokens(provider: ProviderKind, request: &ChatRequest) -> u32 {
+    let image_tokens = |image: &ImageAttachment| {
+        // Other formats are counted as a 1080p screenshot
+        let (width, height) = png_dimensions(&image.data_base64).unwrap_or((1920, 1080));
+        estimate_image_tokens(provider, request.image_detail, width, height)
+    };
+
+    let mut tokens = 3;
+    for msg in &request.messages {
+        tokens += 4 + estimate_text_tokens(&msg.content);
+        tokens += msg.images.iter().map(image_tokens).sum::<u32>();
+    }
+    for round in &request.tool_rounds {
+        tokens += 4 + estimate_text_tokens(&round.assistant_text);
+        for call in &round.calls {
+            tokens += 4 + estimate_text_tokens(&call.name) + estimate_text_tokens(&call.arguments);
+        }
+        for result in &round.results {
+            tokens += 4 + estimate_text_tokens(&result.content);
+            tokens += result.image.as_ref().map(image_tokens).unwrap_or_default();
+        }
+    }
+    for tool in &request.tools {
+        tokens += estimate_text_tokens(&tool.name) + estimate_text_tokens(&tool.description) + estimate_text_tokens(&tool.parameters.to_string());
+    }
+    tokens
+}
+
+/// Cost in USD, or `None` when the model's price isn't known. Local models are free.
+pub fn cost_usd(provider: ProviderKind, model: &str, prompt_tokens: u32, completion_tokens: u32) -> Option<f64> {
+    if provider == ProviderKind::Ollama {
+        return Some(0.0);
+    }
+    let (_, prompt_price, completion_price) = MODEL_PRICES.iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))?;
+    Some((prompt_tokens as f64 * prompt_price + completion_tokens as f64 * completion_price) / 1_000_000.0)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelUsage {
+    pub requests: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+    /// Requests whose usage had to be estimated because the provider didn't report it
+    pub estimated_requests: u32,
+}
+
+/// Usage per day (`YYYY-MM-DD`) and model.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LedgerData {
+    days: BTreeMap<String, BTreeMap<String, ModelUsage>>,
+}
+
+#[derive(Debug)]
+pub struct UsageLedger {
+    path: PathBuf,
+    data: Mutex<LedgerData>,
+}
+
+impl UsageLedger {
+    pub fn load(path: PathBuf) -> Self {
+        let data = std::fs::read_to_string(&path).ok()
+            .and_then(|contents| match serde_json::from_str(&contents) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    error!("Failed to parse usage ledger, starting a new one: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self { path, data: Mutex::new(data) }
+    }
+
+    pub fn record(&self, model: &str, prompt_tokens: u32, completion_tokens: u32, cost_usd: Option<f64>, estimated: bool) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let day = Local::now().date_naive().format("%Y-%m-%d").to_string();
+        let usage = data.days.entry(day).or_default().entry(model.to_string()).or_default();
+        usage.requests += 1;
+        usage.prompt_tokens += prompt_tokens as u64;
+        usage.completion_tokens += completion_tokens as u64;
+        usage.cost_usd += cost_usd.unwrap_or_default();
+        if estimated {
+            usage.estimated_requests += 1;
+        }
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(&*data)?)?;
+        Ok(())
+    }
+
+    pub fn spent_on(&self, day: NaiveDate) -> f64 {
+        let data = self.data.lock().unwrap();
+        data.days.get(&day.format("%Y-%m-%d").to_string())
+            .map(|models| models.values().map(|usage| usage.cost_usd).sum())
+            .unwrap_or_default()
+    }
+
+    pub fn spent_in_month(&self, day: NaiveDate) -> f64 {
+        let prefix = day.format("%Y-%m-").to_string();
+        let data = self.data.lock().unwrap();
+        data.days.iter()
+            .filter(|(date, _)| date.starts_with(&prefix))
+            .flat_map(|(_, models)| models.values())
+            .map(|usage| usage.cost_usd)
+            .sum()
+    }
+
+    fn days_in_month(&self, day: NaiveDate) -> BTreeMap<String, BTreeMap<String, ModelUsage>> {
+        let prefix = day.format("%Y-%m-").to_string();
+        let data = self.data.lock().unwrap();
+        data.days.iter()
+            .filter(|(date, _)| date.starts_with(&prefix))
+            .map(|(date, models)| (date.clone(), models.clone()))
+            .collect()
+    }
+}
+
+/// Records a finished request, using the usage the provider reported and falling back to estimates.
+pub fn record_usage(ledger: &UsageLedger, provider: ProviderKind, model: &str, estimated_prompt_tokens: u32, answer: &str, usage: Option<&TokenUsage>) {
+    let prompt_tokens = usage.and_then(|usage| usage.prompt_tokens);
+    let completion_tokens = usage.and_then(|usage| usage.completion_tokens);
+    let estimated = prompt_tokens.is_none() || completion_tokens.is_none();
+    let prompt_tokens = prompt_tokens.unwrap_or(estimated_prompt_tokens);
+    let completion_tokens = completion_tokens.unwrap_or_else(|| estimate_text_tokens(answer));
+
+    let cost = cost_usd(provider, model, prompt_tokens, completion_tokens);
+    if cost.is_none() {
+        warn!("No price known for model {}, its cost isn't counted towards the spend caps", model);
+    }
+    info!("Request used {} prompt and {} completion tokens ({}), cost: {:?}", prompt_tokens, completion_tokens, if estimated { "estimated" } else { "reported" }, cost);
+    if let Err(e) = ledger.record(model, prompt_tokens, completion_tokens, cost, estimated) {
+        error!("Failed to save usage ledger: {}", e);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendCapMode {
+    Block,
+    Warn,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpendCheck {
+    Ok,
+    /// The request may go ahead, but the user should be told
+    Warn(String),
+    Block(String),
+}
+
+/// Checks the configured daily and monthly caps (`daily_spend_cap_usd`, `monthly_spend_cap_usd`)
+/// against what has been spent plus the worst case cost of the next request.
+pub fn check_spend_caps(app_handle: &AppHandle, ledger: &UsageLedger, next_request_cost: f64) -> SpendCheck {
+    let mode = match get_string_from_store(app_handle, "spend_cap_mode").as_deref() {
+        Some("warn") => SpendCapMode::Warn,
+        _ => SpendCapMode::Block,
+    };
+    let daily_cap = get_value_from_store(app_handle, "daily_spend_cap_usd").and_then(|value| value.as_f64());
+    let monthly_cap = get_value_from_store(app_handle, "monthly_spend_cap_usd").and_then(|value| value.as_f64());
+    let today = Local::now().date_naive();
+    evaluate_caps(mode, daily_cap, monthly_cap, ledger.spent_on(today), ledger.spent_in_month(today), next_request_cost)
+}
+
+fn evaluate_caps(mode: SpendCapMode, daily_cap: Option<f64>, monthly_cap: Option<f64>, spent_today: f64, spent_this_month: f64, next_request_cost: f64) -> SpendCheck {
+    let exceeded = [("daily", daily_cap, spent_today), ("monthly", monthly_cap, spent_this_month)]
+        .into_iter()
+        .find_map(|(period, cap, spent)| {
+            let cap = cap.filter(|cap| *cap > 0.0)?;
+            (spent + next_request_cost > cap).then(|| format!(
+                "This request would exceed the {} spend cap of ${:.2} (${:.2} spent so far).", period, cap, spent
+            ))
+        });
+
+    match (exceeded, mode) {
+        (None, _) => SpendCheck::Ok,
+        (Some(message), SpendCapMode::Warn) => SpendCheck::Warn(message),
+        (Some(message), SpendCapMode::Block) => SpendCheck::Block(message),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageSummary {
+    spent_today_usd: f64,
+    spent_this_month_usd: f64,
+    days: BTreeMap<String, BTreeMap<String, ModelUsage>>,
+}
+
+#[tauri::command]
+pub fn get_usage_summary(ledger: State<'_, UsageLedger>) -> UsageSummary {
+    let today = Local::now().date_naive();
+    UsageSummary {
+        spent_today_usd: ledger.spent_on(today),
+        spent_this_month_usd: ledger.spent_in_month(today),
+        days: ledger.days_in_month(today.with_day(1).unwrap_or(today)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openai_image_tokens() {
+        // Examples from OpenAI's vision pricing docs
+        assert_eq!(openai_image_tokens(ImageDetail::High, 1024, 1024), 765);
+        assert_eq!(openai_image_tokens(ImageDetail::High, 2048, 4096), 1105);
+        assert_eq!(openai_image_tokens(ImageDetail::Low, 4096, 8192), 85);
+    }
+
+    #[test]
+    fn test_cost_uses_longest_matching_prefix() {
+        let cost = cost_usd(ProviderKind::OpenAi, "gpt-4o-mini-2024-07-18", 1_000_000, 0).unwrap();
+        assert!((cost - 0.15).abs() < 1e-9);
+        assert_eq!(cost_usd(ProviderKind::Ollama, "llava", 1000, 1000), Some(0.0));
+        assert_eq!(cost_usd(ProviderKind::OpenAi, "some-new-model", 1000, 1000), None);
+    }
+
+    #[test]
+    fn test_spend_caps() {
+        assert_eq!(evaluate_caps(SpendCapMode::Block, Some(1.0), None, 0.5, 0.5, 0.1), SpendCheck::Ok);
+        assert!(matches!(evaluate_caps(SpendCapMode::Block, Some(1.0), None, 0.95, 0.95, 0.1), SpendCheck::Block(_)));
+        assert!(matches!(evaluate_caps(SpendCapMode::Warn, None, Some(10.0), 0.0, 9.95, 0.1), SpendCheck::Warn(_)));
+        // A cap of zero means no cap
+        assert_eq!(evaluate_caps(SpendCapMode::Block, Some(0.0), Some(0.0), 5.0, 50.0, 1.0), SpendCheck::Ok);
+    }
+}
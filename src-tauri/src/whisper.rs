@@ -0,0 +1,464 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Manager, State};
+use tokio::io::AsyncWriteExt;
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperError};
+use crate::audio_utils::TARGET_SAMPLE_RATE;
+use crate::gpt::GptClient;
+use crate::live_transcription::Word;
+use crate::recorder::{AudioRecording, Recorder};
+use crate::stores::{get_string_from_store, set_in_store};
+
+const MODEL_REPO_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp";
+const DEFAULT_MODEL: &str = "base.en";
+const MANIFEST_FILE: &str = "manifest.json";
+const HASH_BUFFER_SIZE: usize = 1 << 20;
+
+/// The SHA-256 and size of a model file, as published with the repository's LFS pointers.
+/// `scripts/pin_whisper_checksums.sh` prints the catalog rows with them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PinnedChecksum {
+    pub sha256: &'static str,
+    pub size: u64,
+}
+
+/// A ggml Whisper model that can be downloaded. Files are only installed if they match the
+/// checksum pinned here, so a compromised or changed repository can't swap the model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CatalogModel {
+    pub name: &'static str,
+    /// Roughly, for showing before the download starts
+    pub size_mb: u32,
+    pub english_only: bool,
+    pub quantized: bool,
+    /// None for models whose checksum hasn't been pinned yet, which can't be installed
+    pub checksum: Option<PinnedChecksum>,
+}
+
+impl CatalogModel {
+    fn file_name(&self) -> String {
+        format!("ggml-{}.bin", self.name)
+    }
+
+    fn url(&self) -> String {
+        format!("{}/resolve/main/{}", MODEL_REPO_URL, self.file_name())
+    }
+
+    fn expected_checksum(&self) -> Result<Checksum> {
+        let pinned = self.checksum.ok_or_else(|| anyhow!("The {} model has no pinned checksum, so it can't be verified", self.name))?;
+        Ok(Checksum { sha256: pinned.sha256.to_string(), size: pinned.size })
+    }
+
+    /// Fails unless `checksum` is the pinned one.
+    fn verify(&self, checksum: &Checksum) -> Result<()> {
+        let expected = self.expected_checksum()?;
+        if *checksum != expected {
+            bail!(
+                "This isn't the {} model: expected SHA-256 {} ({} bytes), got {} ({} bytes)",
+                self.name, expected.sha256, expected.size, checksum.sha256, checksum.size
+            );
+        }
+        Ok(())
+    }
+}
+
+const fn model(name: &'static str, size_mb: u32, english_only: bool, quantized: bool, checksum: Option<PinnedChecksum>) -> CatalogModel {
+    CatalogModel { name, size_mb, english_only, quantized, checksum }
+}
+
+pub const CATALOG: &[CatalogModel] = &[
+    model("tiny", 75, false, false, None),
+    model("tiny.en", 75, true, false, None),
+    model("tiny-q5_1", 31, false, true, None),
+    model("tiny.en-q5_1", 31, true, true, None),
+    model("base", 142, false, false, None),
+    model("base.en", 142, true, false, None),
+    model("base-q5_1", 57, false, true, None),
+    model("base.en-q5_1", 57, true, true, None),
+    model("small", 466, false, false, None),
+    model("small.en", 466, true, false, None),
+    model("small-q5_1", 181, false, true, None),
+    model("small.en-q5_1", 181, true, true, None),
+    model("medium", 1500, false, false, None),
+    model("medium.en", 1500, true, false, None),
+    model("medium-q5_0", 514, false, true, None),
+    model("medium.en-q5_0", 514, true, true, None),
+    model("large-v3", 2900, false, false, None),
+    model("large-v3-q5_0", 1080, false, true, None),
+];
+
+pub fn catalog_model(name: &str) -> Result<&'static CatalogModel> {
+    CATALOG.iter().find(|model| model.name == name).ok_or_else(|| anyhow!("Unknown Whisper model {}", name))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub sha256: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledModel {
+    pub name: String,
+    pub path: PathBuf,
+    pub checksum: Checksum,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStatus {
+    #[serde(flatten)]
+    pub model: CatalogModel,
+    pub installed: Option<InstalledModel>,
+    pub selected: bool,
+}
+
+fn hash_file(path: &Path) -> Result<Checksum> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok(Checksum { sha256: format!("{:x}", hasher.finalize()), size })
+}
+
+/// The Whisper models in the app data dir, and the one loaded for transcription.
+pub struct ModelManager {
+    dir: PathBuf,
+    installed: Mutex<Vec<InstalledModel>>,
+    /// Loading a model takes seconds, so the last one used stays loaded
+    context: Mutex<Option<(String, WhisperContext)>>,
+}
+
+impl ModelManager {
+    pub fn load(dir: PathBuf) -> Self {
+        let installed: Vec<InstalledModel> = match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                error!("Failed to parse the Whisper model manifest: {}", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        // A file that was deleted or changed outside the app, or that doesn't match the pinned
+        // checksum (e.g. one imported unverified before checksums were pinned), has to be installed again
+        let installed = installed.into_iter()
+            .filter(|model| {
+                let pinned = catalog_model(&model.name).and_then(|catalog| catalog.verify(&model.checksum));
+                match fs::metadata(&model.path) {
+                    Ok(metadata) if metadata.len() == model.checksum.size && pinned.is_ok() => true,
+                    _ => {
+                        warn!("Whisper model {} is missing, changed or unverified, forgetting it", model.name);
+                        false
+                    }
+                }
+            })
+            .collect();
+        Self { dir, installed: Mutex::new(installed), context: Mutex::new(None) }
+    }
+
+    pub fn installed(&self, name: &str) -> Option<InstalledModel> {
+        self.installed.lock().unwrap().iter().find(|model| model.name == name).cloned()
+    }
+
+    pub fn statuses(&self, selected: &str) -> Vec<ModelStatus> {
+        CATALOG.iter()
+            .map(|model| ModelStatus { model: *model, installed: self.installed(model.name), selected: model.name == selected })
+            .collect()
+    }
+
+    fn record(&self, model: InstalledModel) -> Result<InstalledModel> {
+        let mut installed = self.installed.lock().unwrap();
+        installed.retain(|existing| existing.name != model.name);
+        installed.push(model.clone());
+        self.save(&installed)?;
+        Ok(model)
+    }
+
+    fn save(&self, installed: &[InstalledModel]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(MANIFEST_FILE), serde_json::to_string_pretty(installed)?)?;
+        Ok(())
+    }
+
+    /// Downloads the model next to the others, only keeping it if it matches the pinned checksum.
+    pub async fn download(&self, app_handle: &AppHandle, client: &Client, model: &CatalogModel) -> Result<InstalledModel> {
+        let expected = model.expected_checksum()?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(model.file_name());
+        let partial = self.dir.join(format!("{}.part", model.file_name()));
+        info!("Downloading Whisper model {} ({} bytes)", model.name, expected.size);
+
+        let mut response = client.get(model.url()).send().await?.error_for_status()?;
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        let mut downloaded = 0u64;
+        let mut reported_percent = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+            let percent = (downloaded * 100 / expected.size.max(1)).min(100);
+            if percent > reported_percent {
+                reported_percent = percent;
+                let _ = app_handle.emit_all("model_download_progress", json!({
+                    "model": model.name,
+                    "downloaded": downloaded,
+                    "total": expected.size,
+                }));
+            }
+        }
+        file.sync_all().await?;
+        drop(file);
+
+        let checksum = Checksum { sha256: format!("{:x}", hasher.finalize()), size: downloaded };
+        if let Err(e) = model.verify(&checksum) {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.context(format!("The download of {} is corrupt", model.name)));
+        }
+        self.unload(model.name);
+        tokio::fs::rename(&partial, &path).await?;
+        info!("Installed Whisper model {}", model.name);
+        self.record(InstalledModel { name: model.name.to_string(), path, checksum })
+    }
+
+    /// Copies a model file the user already has, if it matches the pinned checksum. This needs no
+    /// network, so imports also work offline.
+    pub async fn import(&self, model: &CatalogModel, source: PathBuf) -> Result<InstalledModel> {
+        let source_for_hash = source.clone();
+        let checksum = tauri::async_runtime::spawn_blocking(move || hash_file(&source_for_hash)).await??;
+        model.verify(&checksum).with_context(|| format!("Can't import {}", source.display()))?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(model.file_name());
+        // The loaded model may be the file that's about to be replaced
+        self.unload(model.name);
+        if source != path {
+            tokio::fs::copy(&source, &path).await.with_context(|| format!("Failed to copy {}", source.display()))?;
+        }
+        info!("Imported Whisper model {} from {}", model.name, source.display());
+        self.record(InstalledModel { name: model.name.to_string(), path, checksum })
+    }
+
+    /// Makes the next transcription load the model's file again.
+    fn unload(&self, name: &str) {
+        let mut context = self.context.lock().unwrap();
+        if context.as_ref().is_some_and(|(loaded, _)| loaded == name) {
+            *context = None;
+        }
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let mut installed = self.installed.lock().unwrap();
+        let index = installed.iter().position(|model| model.name == name)
+            .ok_or_else(|| anyhow!("Whisper model {} isn't installed", name))?;
+        let model = installed.remove(index);
+        self.unload(&model.name);
+        if let Err(e) = fs::remove_file(&model.path) {
+            warn!("Failed to delete {}: {}", model.path.display(), e);
+        }
+        self.save(&installed)
+    }
+
+    /// Transcribes 16 kHz mono audio. `language` is a code like "en", or "auto" to detect it.
+    pub fn transcribe(&self, name: &str, language: &str, recording: &AudioRecording) -> Result<String> {
+        if recording.config.sample_rate.0 != TARGET_SAMPLE_RATE as u32 || recording.config.channels != 1 {
+            bail!(
+                "Whisper needs {} Hz mono audio, got {} Hz with {} channel(s)",
+                TARGET_SAMPLE_RATE, recording.config.sample_rate.0, recording.config.channels
+            );
+        }
+        let segments = self.segments(name, language, &recording.audio_data, false)?;
+        Ok(segments.iter().map(|segment| segment.text.as_str()).collect::<String>().trim().to_string())
+    }
+
+    /// Transcribes 16 kHz mono audio word by word, with times relative to its start.
+    pub fn transcribe_words(&self, name: &str, language: &str, audio: &[f32]) -> Result<Vec<Word>> {
+        Ok(self.segments(name, language, audio, true)?
+            .into_iter()
+            .filter(|segment| !segment.text.trim().is_empty())
+            .map(|segment| Word {
+                word: segment.text.trim().to_string(),
+                start: segment.start,
+                end: segment.end,
+                confidence: segment.confidence,
+                speaker: 0,
+            })
+            .collect())
+    }
+
+    /// Runs the model over the audio. With `split_words`, every segment is a single word.
+    fn segments(&self, name: &str, language: &str, audio: &[f32], split_words: bool) -> Result<Vec<Segment>> {
+        if audio.is_empty() {
+            return Ok(Vec::new());
+        }
+        let installed = self.installed(name).ok_or_else(|| anyhow!("Whisper model {} isn't installed", name))?;
+        let language = if catalog_model(name)?.english_only { "en" } else { language };
+
+        let mut context = self.context.lock().unwrap();
+        if !context.as_ref().is_some_and(|(loaded, _)| loaded == name) {
+            info!("Loading Whisper model {}", name);
+            let path = installed.path.to_str().ok_or_else(|| anyhow!("Invalid model path {}", installed.path.display()))?;
+            let loaded = WhisperContext::new(path).map_err(|e| anyhow!("Failed to load Whisper model {}: {:?}", name, e))?;
+            *context = Some((name.to_string(), loaded));
+        }
+        let (_, whisper) = context.as_ref().unwrap();
+        let mut state = whisper.create_state().map_err(|e| anyhow!("Failed to create the Whisper state: {:?}", e))?;
+
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        params.set_language(Some(language));
+        params.set_print_progress(false);
+        params.set_print_realtime(false);
+        params.set_print_special(false);
+        params.set_print_timestamps(false);
+        if split_words {
+            params.set_token_timestamps(true);
+            params.set_split_on_word(true);
+            params.set_max_len(1);
+        }
+        let failed = |e: WhisperError| anyhow!("Transcription failed: {:?}", e);
+        state.full(params, audio).map_err(failed)?;
+
+        let mut segments = Vec::new();
+        for segment in 0..state.full_n_segments().map_err(failed)? {
+            // Special tokens like [_BEG_] have no say in how sure the model is about the words
+            let mut probabilities = Vec::new();
+            for token in 0..state.full_n_tokens(segment).map_err(failed)? {
+                let special = state.full_get_token_text(segment, token)
+                    .is_ok_and(|text| text.starts_with("[_") || text.starts_with("<|"));
+                if !special {
+                    probabilities.push(state.full_get_token_prob(segment, token).map_err(failed)?);
+                }
+            }
+            segments.push(Segment {
+                text: state.full_get_segment_text(segment).map_err(failed)?,
+                // Whisper counts in centiseconds
+                start: state.full_get_segment_t0(segment).map_err(failed)? as f64 / 100.0,
+                end: state.full_get_segment_t1(segment).map_err(failed)? as f64 / 100.0,
+                confidence: if probabilities.is_empty() { 0.0 } else { probabilities.iter().sum::<f32>() / probabilities.len() as f32 },
+            });
+        }
+        Ok(segments)
+    }
+}
+
+struct Segment {
+    text: String,
+    start: f64,
+    end: f64,
+    confidence: f32,
+}
+
+/// The model chosen on the settings page (`whisper_model`).
+pub fn selected_model(app_handle: &AppHandle) -> String {
+    get_string_from_store(app_handle, "whisper_model").filter(|name| !name.is_empty()).unwrap_or_else(|| DEFAULT_MODEL.to_string())
+}
+
+/// The spoken language (`whisper_language`), detected by default.
+pub fn selected_language(app_handle: &AppHandle) -> String {
+    get_string_from_store(app_handle, "whisper_language").filter(|code| !code.is_empty()).unwrap_or_else(|| "auto".to_string())
+}
+
+#[tauri::command]
+pub fn list_whisper_models(app_handle: AppHandle, models: State<'_, ModelManager>) -> Vec<ModelStatus> {
+    models.statuses(&selected_model(&app_handle))
+}
+
+/// Makes sure a model is installed, by importing `import_path` or downloading it. Without a
+/// `model`, the selected one is used, which is what the first run page relies on.
+#[tauri::command]
+pub async fn handle_model_file(
+    app_handle: AppHandle,
+    models: State<'_, ModelManager>,
+    gpt_client: State<'_, GptClient>,
+    model: Option<String>,
+    import_path: Option<String>,
+) -> Result<InstalledModel, String> {
+    let name = model.clone().unwrap_or_else(|| selected_model(&app_handle));
+    let catalog = catalog_model(&name).map_err(|e| e.to_string())?;
+    let client = gpt_client.transport().client();
+
+    let installed = match (import_path, models.installed(&name)) {
+        (Some(path), _) => models.import(catalog, PathBuf::from(path)).await,
+        (None, Some(installed)) => Ok(installed),
+        (None, None) => models.download(&app_handle, &client, catalog).await,
+    }.map_err(|e| {
+        error!("Failed to install Whisper model {}: {:#}", name, e);
+        format!("{:#}", e)
+    })?;
+
+    if model.is_some() {
+        set_in_store(&app_handle, "whisper_model".to_string(), Value::String(name));
+    }
+    Ok(installed)
+}
+
+#[tauri::command]
+pub fn delete_whisper_model(models: State<'_, ModelManager>, name: String) -> Result<(), String> {
+    models.delete(&name).map_err(|e| format!("{:#}", e))
+}
+
+/// Transcribes the last recording with the selected model.
+#[tauri::command]
+pub async fn transcribe_recording(app_handle: AppHandle, recorder: State<'_, Recorder>) -> Result<String, String> {
+    let recording = recorder.take_last_recording().ok_or("Nothing has been recorded")?;
+    let name = selected_model(&app_handle);
+    let language = selected_language(&app_handle);
+    tauri::async_runtime::spawn_blocking(move || {
+        app_handle.state::<ModelManager>().transcribe(&name, &language, &recording)
+    })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_and_checksums() {
+        let base = catalog_model("base.en").unwrap();
+        assert_eq!(base.url(), "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-base.en.bin");
+        assert!(catalog_model("huge").is_err());
+
+        let path = std::env::temp_dir().join(format!("derby-whisper-{}.bin", std::process::id()));
+        fs::write(&path, "abc").unwrap();
+        let checksum = hash_file(&path).unwrap();
+        assert_eq!(checksum, Checksum {
+            sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+            size: 3,
+        });
+        fs::remove_file(&path).unwrap();
+
+        let pinned = |sha256, size| CatalogModel { checksum: Some(PinnedChecksum { sha256, size }), ..*base };
+        assert!(pinned("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", 3).verify(&checksum).is_ok());
+        assert!(pinned("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", 4).verify(&checksum).is_err());
+        assert!(pinned("0000000000000000000000000000000000000000000000000000000000000000", 3).verify(&checksum).is_err());
+        // Nothing unverified is installed
+        assert!(CatalogModel { checksum: None, ..*base }.verify(&checksum).is_err());
+    }
+
+    #[test]
+    fn test_every_catalog_model_is_pinned() {
+        for model in CATALOG {
+            let checksum = model.checksum.unwrap_or_else(|| panic!("{} has no pinned checksum, run scripts/pin_whisper_checksums.sh", model.name));
+            assert_eq!(checksum.sha256.len(), 64, "{}", model.name);
+            assert!(checksum.sha256.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()), "{}", model.name);
+            assert!(checksum.size > 0, "{}", model.name);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+export type AnswerBlock =
+  | { type: "paragraph"; text: string }
+  | { type: "code"; language: string | null; code: string }
+  | { type: "list"; ordered: boolean; items: string[] }
+  | { type: "email"; to: string | null; subject: string; body: string };
+
+export interface AskResponse {
+  answer: string;
+  blocks: AnswerBlock[];
+}
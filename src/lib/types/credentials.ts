@@ -0,0 +1,13 @@
+export interface ModelAccess {
+  model: string;
+  accessible: boolean;
+  vision: boolean;
+}
+
+export interface ValidationReport {
+  provider: string;
+  valid: boolean;
+  message: string | null;
+  models: ModelAccess[];
+  vision_model: string | null;
+}
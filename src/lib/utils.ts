@@ -7,8 +7,8 @@ export function cn(...inputs: ClassValue[]) {
   return twMerge(clsx(inputs));
 }
 
-export function readEnvVariable(variableName: string): Promise<string> {
-  return invoke("get_env_var", { key: variableName }).then((response: unknown) => {
+export function readConfigValue(key: string): Promise<string> {
+  return invoke("get_config_value", { key }).then((response: unknown) => {
     return response as string;
   }).catch((error) => {
     throw new Error(error);